@@ -0,0 +1,60 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, bail, Context};
+
+use crate::XkbProperty;
+
+/// An input device the settings go to, with the values it uses regardless of locale1.
+#[derive(Clone)]
+pub struct Device {
+    pub identifier: String,
+    pub overrides: Vec<(XkbProperty, String)>,
+}
+
+impl Device {
+    pub fn new(identifier: String) -> Self {
+        Self { identifier, overrides: Vec::new() }
+    }
+
+    /// The value of `prop` for this device: its override, or else `value` from locale1.
+    pub fn value<'a>(&'a self, prop: XkbProperty, value: &'a str) -> &'a str {
+        self.overrides.iter()
+            .find(|(p, _)| *p == prop)
+            .map_or(value, |(_, v)| v.as_str())
+    }
+}
+
+/// Reads the per-device overrides from the TOML file at `path`, a table for each device
+/// identifier with any of `layout`, `model`, `variant` and `options`:
+///
+/// ```toml
+/// ["12951:18804:ZSA_Technology_Labs_ErgoDox_EZ"]
+/// options = "caps:none"
+/// ```
+pub fn load(path: &Path) -> anyhow::Result<Vec<Device>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let doc: toml_edit::DocumentMut = text.parse().with_context(|| format!("Could not parse {}", path.display()))?;
+
+    let mut devices = Vec::new();
+    for (identifier, item) in doc.iter() {
+        let table = item.as_table_like()
+            .ok_or_else(|| anyhow!("{}: '{}' should be a table of xkb settings", path.display(), identifier))?;
+
+        let mut device = Device::new(identifier.to_owned());
+        for (key, value) in table.iter() {
+            let prop = match key {
+                "layout" => XkbProperty::Layout,
+                "model" => XkbProperty::Model,
+                "variant" => XkbProperty::Variant,
+                "options" => XkbProperty::Options,
+                _ => bail!("{}: unknown key '{}' for '{}', expected layout, model, variant or options", path.display(), key, identifier),
+            };
+            let value = value.as_str()
+                .ok_or_else(|| anyhow!("{}: '{}' of '{}' should be a string", path.display(), key, identifier))?;
+            device.overrides.push((prop, value.to_owned()));
+        }
+        devices.push(device);
+    }
+
+    Ok(devices)
+}
@@ -0,0 +1,123 @@
+use std::path::Path;
+use std::{env, fs, io};
+use anyhow::Context;
+
+use crate::{XkbProperties, XkbProperty};
+
+/// Where systemd-localed itself writes the settings, readable without it running.
+pub const XORG_KEYBOARD_CONF: &str = "/etc/X11/xorg.conf.d/00-keyboard.conf";
+
+/// Reads the `Option "Xkb..." "<value>"` lines of an xorg.conf file, like the one localed writes:
+///
+/// ```text
+/// Section "InputClass"
+///         Identifier "system-keyboard"
+///         MatchIsKeyboard "on"
+///         Option "XkbLayout" "us,fi"
+/// EndSection
+/// ```
+///
+/// None if the file doesn't exist or sets none of them.
+pub fn from_file(path: &Path) -> anyhow::Result<Option<XkbProperties>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+
+    let mut props = None;
+    for line in text.lines() {
+        // Keywords and option names are case insensitive in xorg.conf.
+        let Some((keyword, rest)) = line.trim().split_once(|c: char| c.is_whitespace()) else { continue };
+        if !keyword.eq_ignore_ascii_case("option") {
+            continue;
+        }
+        // The quoted strings are at the odd positions between the quotes.
+        let mut quoted = rest.split('"').skip(1).step_by(2);
+        let (Some(key), Some(value)) = (quoted.next(), quoted.next()) else { continue };
+        let prop = match key.to_ascii_lowercase().as_str() {
+            "xkblayout" => XkbProperty::Layout,
+            "xkbmodel" => XkbProperty::Model,
+            "xkbvariant" => XkbProperty::Variant,
+            "xkboptions" => XkbProperty::Options,
+            _ => continue,
+        };
+        props.get_or_insert_with(XkbProperties::default).set(prop, value.to_owned());
+    }
+    Ok(props)
+}
+
+/// The settings in the `XKB_DEFAULT_LAYOUT`, `_MODEL`, `_VARIANT` and `_OPTIONS` environment
+/// variables libxkbcommon goes by, or None if none of them is set.
+pub fn from_env() -> Option<XkbProperties> {
+    let mut props = None;
+    for (var, prop) in [
+        ("XKB_DEFAULT_LAYOUT", XkbProperty::Layout),
+        ("XKB_DEFAULT_MODEL", XkbProperty::Model),
+        ("XKB_DEFAULT_VARIANT", XkbProperty::Variant),
+        ("XKB_DEFAULT_OPTIONS", XkbProperty::Options),
+    ] {
+        if let Ok(value) = env::var(var) {
+            props.get_or_insert_with(XkbProperties::default).set(prop, value);
+        }
+    }
+    props
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(props: &XkbProperties) -> [&str; 4] {
+        [&props.layout, &props.model, &props.variant, &props.options]
+    }
+
+    #[test]
+    fn reads_xorg_conf() {
+        let path = env::temp_dir().join(format!("locale1-xkb-config-test-{}.conf", std::process::id()));
+        assert!(from_file(&path).unwrap().is_none());
+
+        fs::write(&path, r#"# Written by systemd-localed(8), read by systemd-localed and Xorg. It's
+# probably wise not to edit this file manually. Use localectl(1) to
+# instruct systemd-localed to update it.
+Section "InputClass"
+        Identifier "system-keyboard"
+        MatchIsKeyboard "on"
+        Option "XkbLayout" "us,fi"
+        Option "XkbModel" "pc105"
+	option	"xkbvariant"	",nodeadkeys"
+        Option "XkbOptions" "grp:alt_shift_toggle,ctrl:nocaps"
+        Option "AutoRepeat" "200 30"
+EndSection
+"#).unwrap();
+        let props = from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(fields(&props.unwrap()), ["us,fi", "pc105", ",nodeadkeys", "grp:alt_shift_toggle,ctrl:nocaps"]);
+    }
+
+    #[test]
+    fn reads_xorg_conf_without_xkb_options() {
+        let path = env::temp_dir().join(format!("locale1-xkb-config-test-empty-{}.conf", std::process::id()));
+        fs::write(&path, "Section \"InputClass\"\n        Identifier \"touchpad\"\n        Option \"Tapping\" \"on\"\n        Option \"XkbLayout\"\nEndSection\n").unwrap();
+        let props = from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(props.is_none());
+    }
+
+    /// The only test touching XKB_DEFAULT_*, as the environment is process wide.
+    #[test]
+    fn reads_env() {
+        for var in ["XKB_DEFAULT_LAYOUT", "XKB_DEFAULT_MODEL", "XKB_DEFAULT_VARIANT", "XKB_DEFAULT_OPTIONS"] {
+            env::remove_var(var);
+        }
+        assert!(from_env().is_none());
+
+        env::set_var("XKB_DEFAULT_LAYOUT", "de,us");
+        env::set_var("XKB_DEFAULT_OPTIONS", "ctrl:nocaps");
+        assert_eq!(fields(&from_env().unwrap()), ["de,us", "", "", "ctrl:nocaps"]);
+
+        env::set_var("XKB_DEFAULT_VARIANT", "");
+        env::set_var("XKB_DEFAULT_MODEL", "pc104");
+        assert_eq!(fields(&from_env().unwrap()), ["de,us", "pc104", "", "ctrl:nocaps"]);
+    }
+}
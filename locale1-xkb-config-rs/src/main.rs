@@ -1,15 +1,29 @@
 #![forbid(unsafe_code)]
 // Rust port of https://github.com/alebastr/sway-systemd/blob/main/src/locale1-xkb-config
+use std::{env, fs, io, thread};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use argh::FromArgs;
-use anyhow::Context;
-use tracing::{debug, error, info};
+use async_io::Timer;
+use anyhow::{bail, Context};
+use futures_lite::{future, StreamExt};
+use tracing::{debug, error, info, warn};
 use zbus::fdo;
 use zbus_macros::proxy;
 
+mod config;
+mod fallback;
+#[cfg(feature = "systemd")]
+mod systemd;
+use crate::config::Device;
+
 #[proxy(
     interface = "org.freedesktop.locale1",
     default_path = "/org/freedesktop/locale1",
-    blocking_name = "Locale1Blocking",
+    gen_blocking = false,
 )]
 trait Locale1 {
     #[zbus(property)]
@@ -25,6 +39,7 @@ trait Locale1 {
     fn x11_options(&self) -> fdo::Result<String>;
 }
 
+#[derive(Clone, Default)]
 struct XkbProperties {
     layout: String,
     model: String,
@@ -32,36 +47,50 @@ struct XkbProperties {
     options: String,
 }
 
-struct DbusLocale1(zbus::blocking::Connection);
+impl XkbProperties {
+    fn set(&mut self, prop: XkbProperty, value: String) {
+        match prop {
+            XkbProperty::Layout => self.layout = value,
+            XkbProperty::Model => self.model = value,
+            XkbProperty::Variant => self.variant = value,
+            XkbProperty::Options => self.options = value,
+        }
+    }
+}
+
+struct DbusLocale1(zbus::Connection);
 
 impl DbusLocale1 {
-    pub fn new() -> anyhow::Result<Self> {
-        Ok(Self(zbus::blocking::Connection::system()?))
+    pub async fn new() -> anyhow::Result<Self> {
+        Ok(Self(zbus::Connection::system().await?))
     }
 
-    pub fn properties_changed_stream(&self) -> anyhow::Result<zbus::blocking::fdo::PropertiesChangedIterator> {
-        let proxy = zbus::blocking::fdo::PropertiesProxy::builder(&self.0)
+    pub async fn properties_changed_stream(&self) -> anyhow::Result<fdo::PropertiesChangedStream<'static>> {
+        let proxy = fdo::PropertiesProxy::builder(&self.0)
             .destination("org.freedesktop.locale1")?
             .path("/org/freedesktop/locale1")?
-            .build()?;
-        Ok(proxy.receive_properties_changed()?)
+            .build()
+            .await?;
+        Ok(proxy.receive_properties_changed().await?)
     }
 
-    pub fn get_xkb_properties(&self) -> anyhow::Result<XkbProperties> {
-        let proxy = Locale1Blocking::builder(&self.0)
+    pub async fn get_xkb_properties(&self) -> anyhow::Result<XkbProperties> {
+        let proxy = Locale1Proxy::builder(&self.0)
             .destination("org.freedesktop.locale1")?
             .cache_properties(zbus::proxy::CacheProperties::No)
-            .build()?;
+            .build()
+            .await?;
 
         Ok(XkbProperties {
-            layout: proxy.x11_layout()?,
-            model: proxy.x11_model()?,
-            variant: proxy.x11_variant()?,
-            options: proxy.x11_options()?,
+            layout: proxy.x11_layout().await?,
+            model: proxy.x11_model().await?,
+            variant: proxy.x11_variant().await?,
+            options: proxy.x11_options().await?,
         })
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 enum XkbProperty {
     Layout,
     Model,
@@ -80,72 +109,845 @@ impl AsRef<str> for XkbProperty {
     }
 }
 
-struct SwayIpc(swayipc::Connection);
+struct SwayIpc {
+    /// None with --dry-run, which prints the commands instead.
+    conn: Option<swayipc::Connection>,
+    /// Times to retry failed commands, waiting `retry_delay` before each.
+    retry_count: u32,
+    retry_delay: Duration,
+    /// Whether to leave settings locale1 has no value for as they are.
+    skip_empty: bool,
+    /// The values last applied to each device, as sway reconfigures the keymap even when nothing
+    /// changes, resetting e.g. key repeat.
+    applied: HashMap<(String, XkbProperty), String>,
+    /// The settings last rejected, as invalid values or by sway, which the devices haven't got.
+    held_back: HashSet<(String, XkbProperty)>,
+}
+
+/// How long to wait for sway to come back after losing the connection.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 impl SwayIpc {
-    pub fn new() -> anyhow::Result<Self> {
-        Ok(Self(swayipc::Connection::new()?))
+    pub async fn new(wait: Duration, retry_count: u32, retry_delay: Duration, on_empty: OnEmpty) -> anyhow::Result<Self> {
+        Ok(Self {
+            conn: Some(connect(wait).await?),
+            retry_count,
+            retry_delay,
+            skip_empty: matches!(on_empty, OnEmpty::Skip),
+            applied: HashMap::new(),
+            held_back: HashSet::new(),
+        })
+    }
+
+    pub fn dry_run(on_empty: OnEmpty) -> Self {
+        Self {
+            conn: None,
+            retry_count: 0,
+            retry_delay: Duration::ZERO,
+            skip_empty: matches!(on_empty, OnEmpty::Skip),
+            applied: HashMap::new(),
+            held_back: HashSet::new(),
+        }
+    }
+
+    /// Sets all of `props` on each of `devices` in order, unless the device overrides them, with a
+    /// single IPC message, sway runs `;` separated commands in one go. Returns whether the
+    /// connection had to be made anew, in which case sway may have lost the earlier settings.
+    pub async fn apply_batch<S: AsRef<str>>(&mut self, devices: &[Device], props: &[(XkbProperty, S)]) -> anyhow::Result<bool> {
+        let mut cmds = Vec::new();
+        let mut values = Vec::new();
+        for device in devices {
+            for (prop, value) in props {
+                if self.skip_empty && value.as_ref().is_empty() && !device.overrides.iter().any(|(p, _)| p == prop) {
+                    info!(device = device.identifier, property = prop.as_ref(), "Skipping xkb value, as locale1 has none");
+                    continue;
+                }
+                let value = device.value(*prop, value.as_ref());
+                let key = (device.identifier.clone(), *prop);
+                let cmd = match input_command(&device.identifier, *prop, value) {
+                    Ok(cmd) => cmd,
+                    Err(e) => {
+                        warn!(device = device.identifier, property = prop.as_ref(), value, "Ignoring {}", e);
+                        self.held_back.insert(key);
+                        continue;
+                    }
+                };
+                self.held_back.remove(&key);
+                if self.applied.get(&key).is_some_and(|applied| applied == value) {
+                    debug!(device = device.identifier, property = prop.as_ref(), value, "Skipping unchanged xkb value");
+                    continue;
+                }
+                cmds.push(cmd);
+                values.push((key, value.to_owned()));
+            }
+        }
+        if cmds.is_empty() {
+            return Ok(false);
+        }
+
+        let (reconnected, failed) = self.run_commands(cmds.clone()).await?;
+        if reconnected {
+            // A new sway has none of the values, and the caller applies them all again.
+            self.applied.clear();
+        } else {
+            for ((key, value), cmd) in values.into_iter().zip(&cmds) {
+                if failed.contains(cmd) {
+                    self.held_back.insert(key);
+                } else {
+                    self.applied.insert(key, value);
+                }
+            }
+        }
+        Ok(reconnected)
+    }
+
+    /// The settings held back, e.g. "xkb_layout of type:keyboard", for the service status.
+    #[cfg(any(feature = "systemd", test))]
+    pub fn held_back(&self) -> Vec<String> {
+        let mut held_back: Vec<String> = self.held_back.iter()
+            .map(|(identifier, prop)| format!("{} of {}", prop.as_ref(), identifier))
+            .collect();
+        held_back.sort();
+        held_back
+    }
+
+    /// Checks that sway still answers on the connection the settings go through.
+    pub fn ping(&mut self) -> Result<(), swayipc::Error> {
+        match &mut self.conn {
+            Some(conn) => conn.get_version().map(drop),
+            None => Ok(()),
+        }
+    }
+
+    /// Forgets what was applied to `devices`, for applying the values again to e.g. a newly added
+    /// device that already matched one.
+    pub fn invalidate(&mut self, devices: &[Device]) {
+        self.applied.retain(|(identifier, _), _| !devices.iter().any(|d| d.identifier == *identifier));
+    }
+
+    /// Runs `cmds` in one IPC message, retrying the ones that failed on their own, so that one
+    /// rejected property doesn't hold back the rest. Reconnects if the socket is dead, e.g. after a
+    /// sway restart, and fails if sway doesn't come back. Returns whether it reconnected, along
+    /// with the commands that failed for good.
+    async fn run_commands(&mut self, mut cmds: Vec<String>) -> anyhow::Result<(bool, Vec<String>)> {
+        let mut reconnected = false;
+        for attempt in 0..=self.retry_count {
+            if attempt > 0 {
+                Timer::after(self.retry_delay).await;
+            }
+            let last = attempt == self.retry_count;
+
+            let Some(conn) = &mut self.conn else {
+                for cmd in &cmds {
+                    println!("{}", cmd);
+                }
+                return Ok((false, Vec::new()));
+            };
+
+            let payload = cmds.join("; ");
+            cmds = match conn.run_command(&payload) {
+                Ok(results) => cmds.into_iter().zip(results)
+                    .filter_map(|(cmd, res)| {
+                        let e = res.err()?;
+                        if last {
+                            error!(error = ?e, command = cmd, "Sway command failed");
+                        } else {
+                            debug!(error = ?e, command = cmd, attempt = attempt + 1, "Sway command failed, retrying");
+                        }
+                        Some(cmd)
+                    })
+                    .collect(),
+                Err(e) => {
+                    if last {
+                        error!(error = ?e, command = payload, "Sway command failed");
+                    } else {
+                        debug!(error = ?e, command = payload, attempt = attempt + 1, "Sway command failed, retrying");
+                    }
+                    if matches!(e, swayipc::Error::Io(_)) {
+                        warn!("Lost the sway IPC connection, reconnecting");
+                        self.reconnect().await?;
+                        reconnected = true;
+                    }
+                    cmds
+                },
+            };
+            if cmds.is_empty() {
+                break;
+            }
+        }
+        Ok((reconnected, cmds))
+    }
+
+    /// Connects to sway anew, e.g. after it restarted, waiting for up to RECONNECT_TIMEOUT. A new
+    /// sway has none of the values applied to the old one.
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.conn = Some(connect(RECONNECT_TIMEOUT).await?);
+        self.applied.clear();
+        info!("Reconnected to sway");
+        Ok(())
+    }
+
+    pub async fn set_xkb_properties(&mut self, devices: &[Device], props: &XkbProperties) -> anyhow::Result<bool> {
+        for device in devices {
+            info!("xkb({}): layout '{}' model '{}' variant '{}' options '{}'",
+                  device.identifier,
+                  device.value(XkbProperty::Layout, &props.layout),
+                  device.value(XkbProperty::Model, &props.model),
+                  device.value(XkbProperty::Variant, &props.variant),
+                  device.value(XkbProperty::Options, &props.options));
+        }
+        self.apply_batch(devices, &[
+            (XkbProperty::Layout, &props.layout),
+            (XkbProperty::Model, &props.model),
+            (XkbProperty::Variant, &props.variant),
+            (XkbProperty::Options, &props.options),
+        ]).await
+    }
+}
+
+/// The sway sockets in $XDG_RUNTIME_DIR, newest first. $SWAYSOCK keeps pointing to the old socket
+/// after sway restarts, the new one is found here.
+fn socket_paths() -> Vec<PathBuf> {
+    let Some(dir) = env::var_os("XDG_RUNTIME_DIR") else { return Vec::new() };
+    let mut sockets: Vec<(SystemTime, PathBuf)> = fs::read_dir(dir).into_iter().flatten().flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.starts_with("sway-ipc.") && n.ends_with(".sock")))
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect();
+    sockets.sort_by_key(|(modified, _)| Reverse(*modified));
+    sockets.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Connects to sway, at $SWAYSOCK or else any socket in $XDG_RUNTIME_DIR that answers, retrying
+/// with a growing delay for up to `timeout`.
+async fn connect(timeout: Duration) -> anyhow::Result<swayipc::Connection> {
+    let deadline = Instant::now() + timeout;
+    let mut delay = Duration::from_millis(100);
+    loop {
+        let conn = swayipc::Connection::new().ok().or_else(|| {
+            socket_paths().into_iter().find_map(|path| UnixStream::connect(path).ok()).map(swayipc::Connection::from)
+        });
+        if let Some(conn) = conn {
+            return Ok(conn);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            bail!("Could not connect to sway");
+        }
+        debug!(delay = ?delay, "Waiting for sway");
+        Timer::after(delay.min(deadline - now)).await;
+        delay = (delay * 2).min(Duration::from_secs(5));
+    }
+}
+
+/// Input device types for --device-type, see man sway-input.
+#[derive(Clone, Copy)]
+enum DeviceType {
+    Keyboard,
+    Pointer,
+    Touchpad,
+    Tablet,
+}
+
+impl std::str::FromStr for DeviceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keyboard" => Ok(DeviceType::Keyboard),
+            "pointer" => Ok(DeviceType::Pointer),
+            "touchpad" => Ok(DeviceType::Touchpad),
+            "tablet" => Ok(DeviceType::Tablet),
+            _ => Err("accepts only keyboard, pointer, touchpad or tablet".to_owned()),
+        }
+    }
+}
+
+impl DeviceType {
+    /// The device identifier matching all devices of this type.
+    fn identifier(self) -> String {
+        let input_type = match self {
+            DeviceType::Keyboard => "keyboard",
+            DeviceType::Pointer => "pointer",
+            DeviceType::Touchpad => "touchpad",
+            DeviceType::Tablet => "tablet_tool",
+        };
+        format!("type:{}", input_type)
+    }
+}
+
+/// What to do with settings locale1 has no value for, for --on-empty.
+#[derive(Clone, Copy)]
+enum OnEmpty {
+    Skip,
+    Clear,
+}
+
+impl std::str::FromStr for OnEmpty {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnEmpty::Skip),
+            "clear" => Ok(OnEmpty::Clear),
+            _ => Err("accepts only skip or clear".to_owned()),
+        }
+    }
+}
+
+/// Where the settings come from, for --source.
+#[derive(Clone, Copy)]
+enum Source {
+    /// locale1, or the others in turn when it can't be reached.
+    Auto,
+    Locale1,
+    File,
+    Env,
+}
+
+impl std::str::FromStr for Source {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Source::Auto),
+            "locale1" => Ok(Source::Locale1),
+            "file" => Ok(Source::File),
+            "env" => Ok(Source::Env),
+            _ => Err("accepts only locale1, file, env or auto".to_owned()),
+        }
     }
+}
+
+/// Log line formats for --log-format.
+#[derive(Clone, Copy)]
+enum LogFormat {
+    Full,
+    Compact,
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
 
-    pub fn set_xkb_property(&mut self, device: &str, prop: XkbProperty, value: &str) {
-        let cmd = format!("input {} {} '{}'", device, prop.as_ref(), value);
-        if let Err(e) = self.0.run_command(&cmd) {
-            error!(error = ?e, command = cmd, "Sway command failed");
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("accepts only full, compact or json".to_owned()),
         }
     }
 }
 
+/// Whether `value` looks like a list of xkb layouts, models, variants or options, e.g.
+/// "us,fi" or "grp:alt_shift_toggle,ctrl:nocaps". Anything else, like quotes or spaces, would break
+/// the sway command it goes into.
+fn is_valid_xkb_value(value: &str) -> bool {
+    value.chars().all(|c| c.is_ascii_alphanumeric() || "_-+:,.()".contains(c))
+}
+
+/// Whether `identifier` can go into a sway command unquoted, e.g. "type:keyboard", "*" or
+/// "1:1:AT_Translated_Set_2_keyboard". Sway replaces the spaces in device names with underscores,
+/// so whitespace, quotes, `;` and `,` only come from typos or worse.
+fn is_valid_identifier(identifier: &str) -> bool {
+    !identifier.is_empty()
+        && identifier.chars().all(|c| !c.is_whitespace() && !c.is_control() && !"'\";,[]{}".contains(c))
+}
+
+/// The sway command setting `prop` of `identifier` to `value`, unless either would break out of it.
+/// All xkb settings go through this.
+fn input_command(identifier: &str, prop: XkbProperty, value: &str) -> anyhow::Result<String> {
+    if !is_valid_identifier(identifier) {
+        bail!("invalid device identifier");
+    }
+    if !is_valid_xkb_value(value) {
+        bail!("invalid xkb value");
+    }
+    Ok(format!("input {} {} '{}'", identifier, prop.as_ref(), value))
+}
+
+/// Whether `device` (see man sway-input) refers to `input`.
+fn device_matches(device: &str, input: &swayipc::Input) -> bool {
+    match device.strip_prefix("type:") {
+        Some(input_type) => input.input_type == input_type,
+        None => device == "*" || device == input.identifier,
+    }
+}
+
+/// Input devices as they are added. swayipc only subscribes to events in a blocking manner, so a
+/// thread forwards them, until the subscription fails. Waits for sway like reconnecting does.
+fn added_inputs() -> async_channel::Receiver<swayipc::Input> {
+    let (tx, rx) = async_channel::unbounded();
+    thread::spawn(move || {
+        let res = future::block_on(connect(RECONNECT_TIMEOUT)).and_then(|c| Ok(c.subscribe([swayipc::EventType::Input])?));
+        let events = match res {
+            Ok(events) => events,
+            Err(e) => return error!(error = ?e, "Watching for input devices failed"),
+        };
+        for event in events {
+            match event {
+                Ok(swayipc::Event::Input(e)) if matches!(e.change, swayipc::InputChange::Added) => {
+                    if tx.send_blocking(e.input).is_err() {
+                        return;
+                    }
+                },
+                Ok(_) => {},
+                Err(e) => return error!(error = ?e, "Watching for input devices failed"),
+            }
+        }
+    });
+    rx
+}
+
+/// Connects to the system bus anew after the connection dropped, e.g. with dbus-daemon restarting,
+/// waiting longer after each failed attempt. Returns the new change stream along with the current
+/// properties, which may have changed in the meantime.
+async fn reconnect_dbus() -> (fdo::PropertiesChangedStream<'static>, XkbProperties) {
+    let mut delay = Duration::from_millis(500);
+    loop {
+        Timer::after(delay).await;
+        let res = async {
+            let dbus = DbusLocale1::new().await?;
+            let changes = dbus.properties_changed_stream().await?;
+            let props = dbus.get_xkb_properties().await?;
+            anyhow::Ok((changes, props))
+        }.await;
+        match res {
+            Ok(reconnected) => return reconnected,
+            Err(e) => warn!(error = ?e, "Reconnecting to D-Bus failed, trying again in {:?}", delay * 2),
+        }
+        delay = (delay * 2).min(Duration::from_secs(30));
+    }
+}
+
+/// The settings from locale1, along with the stream of changes to them unless `oneshot`.
+async fn from_locale1(oneshot: bool) -> anyhow::Result<(XkbProperties, Option<fdo::PropertiesChangedStream<'static>>)> {
+    let dbus = DbusLocale1::new().await?;
+    // Subscribed before reading the properties, so that no change goes amiss in between.
+    let changes = if oneshot { None } else { Some(dbus.properties_changed_stream().await?) };
+    Ok((dbus.get_xkb_properties().await?, changes))
+}
+
+/// The settings from `source`, and the stream of changes to them when they came from locale1.
+async fn initial_settings(source: Source, oneshot: bool) -> anyhow::Result<(XkbProperties, Option<fdo::PropertiesChangedStream<'static>>)> {
+    let file = Path::new(fallback::XORG_KEYBOARD_CONF);
+    let (props, changes, from) = match source {
+        Source::Locale1 => {
+            let (props, changes) = from_locale1(oneshot).await.context("D-Bus")?;
+            (props, changes, "locale1".to_owned())
+        },
+        Source::File => {
+            let props = fallback::from_file(file)?.with_context(|| format!("No xkb settings in {}", file.display()))?;
+            (props, None, file.display().to_string())
+        },
+        Source::Env => {
+            let props = fallback::from_env().context("None of the XKB_DEFAULT_* environment variables is set")?;
+            (props, None, "the environment".to_owned())
+        },
+        Source::Auto => match from_locale1(oneshot).await {
+            Ok((props, changes)) => (props, changes, "locale1".to_owned()),
+            Err(e) => {
+                warn!(error = ?e, "Could not read the settings from locale1, falling back");
+                if let Some(props) = fallback::from_file(file)? {
+                    (props, None, file.display().to_string())
+                } else if let Some(props) = fallback::from_env() {
+                    (props, None, "the environment".to_owned())
+                } else {
+                    bail!("No xkb settings in locale1, {} or the XKB_DEFAULT_* environment variables", file.display());
+                }
+            },
+        },
+    };
+
+    info!("Using the xkb settings from {}", from);
+    if changes.is_none() && !oneshot {
+        info!("Only locale1 is watched for changes, exiting once the settings are applied");
+    }
+    Ok((props, changes))
+}
+
+/// What the main loop woke up for.
+enum Wakeup {
+    Locale1(Option<fdo::PropertiesChanged>),
+    InputAdded(Option<swayipc::Input>),
+    /// Time to tell systemd we're alive, from the loop itself so that a stuck loop gets restarted.
+    Watchdog,
+}
+
+/// Keeps sway's xkb settings in line with `props`. Each change returns whether sway had to be
+/// reconnected to, which takes watching for added devices anew.
+struct Applier<'a> {
+    sway: SwayIpc,
+    devices: &'a [Device],
+    props: XkbProperties,
+}
+
+impl<'a> Applier<'a> {
+    fn new(sway: SwayIpc, devices: &'a [Device]) -> Self {
+        Self { sway, devices, props: XkbProperties::default() }
+    }
+
+    /// Applies all of `props`, at startup or with the current settings after reconnecting to D-Bus.
+    async fn replace(&mut self, props: XkbProperties) -> anyhow::Result<bool> {
+        self.props = props;
+        let reconnected = self.sway.set_xkb_properties(self.devices, &self.props).await?;
+        self.notify_status();
+        Ok(reconnected)
+    }
+
+    /// Applies the properties locale1 changed.
+    async fn change(&mut self, batch: &[(XkbProperty, String)]) -> anyhow::Result<bool> {
+        for (prop, value) in batch {
+            self.props.set(*prop, value.clone());
+        }
+        let reconnected = self.sway.apply_batch(self.devices, batch).await?;
+        if reconnected {
+            // A restarted sway went back to its own config for the settings not in the batch.
+            self.sway.set_xkb_properties(self.devices, &self.props).await?;
+        }
+        self.notify_status();
+        Ok(reconnected)
+    }
+
+    /// Applies the settings to `input`, which sway just added, if any of the devices refers to it.
+    async fn input_added(&mut self, input: &swayipc::Input) -> anyhow::Result<bool> {
+        let matching: Vec<Device> = self.devices.iter().filter(|d| device_matches(&d.identifier, input)).cloned().collect();
+        if matching.is_empty() {
+            return Ok(false);
+        }
+        info!("xkb({}): device '{}' added", identifiers(&matching), input.identifier);
+        self.sway.invalidate(&matching);
+        let reconnected = self.sway.set_xkb_properties(&matching, &self.props).await?;
+        if reconnected {
+            // Other devices may have been reset too, apply to all then.
+            self.sway.set_xkb_properties(self.devices, &self.props).await?;
+        }
+        Ok(reconnected)
+    }
+
+    /// Connects to sway anew and applies all of the settings to it, for when the input subscription
+    /// ended, which it does along with sway.
+    async fn reconnect(&mut self) -> anyhow::Result<()> {
+        self.sway.reconnect().await?;
+        self.sway.set_xkb_properties(self.devices, &self.props).await?;
+        Ok(())
+    }
+
+    /// Checks that sway still answers on the connection the settings go through, which is the one
+    /// that can wedge, reconnecting and applying them anew if sway is gone. Returns whether it
+    /// reconnected.
+    async fn ping(&mut self) -> anyhow::Result<bool> {
+        match self.sway.ping() {
+            Ok(()) => Ok(false),
+            Err(swayipc::Error::Io(_)) => {
+                warn!("Lost the sway IPC connection, reconnecting");
+                self.reconnect().await?;
+                Ok(true)
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn notify_status(&self) {
+        #[cfg(feature = "systemd")]
+        systemd::notify_status(&self.props, &self.sway.held_back());
+    }
+}
+
+/// The identifiers of `devices`, for logging.
+fn identifiers(devices: &[Device]) -> String {
+    devices.iter().map(|d| d.identifier.as_str()).collect::<Vec<_>>().join(", ")
+}
+
 /// Sync Sway input configuration with org.freedesktop.locale1.
 #[derive(FromArgs)]
 struct Args {
-    /// control settings for a specific device identifier (see man sway-input; default: type:keyboard)
-    #[argh(option, default="\"type:keyboard\".to_string()")]
-    device: String,
+    /// control settings for a specific device identifier (see man sway-input; default: type:keyboard),
+    /// can be given more than once to apply the same settings to each in order
+    #[argh(option)]
+    device: Vec<String>,
+    /// control settings for all devices of this type, keyboard, pointer, touchpad or tablet,
+    /// unless --device is given (default: keyboard)
+    #[argh(option)]
+    device_type: Option<DeviceType>,
+    /// TOML file of per-device settings that take precedence over locale1, the devices in it get
+    /// the settings too
+    #[argh(option)]
+    config: Option<PathBuf>,
     /// set logging level (default: info)
     #[argh(option, default="tracing::Level::INFO")]
     log_level: tracing::Level,
+    /// log line format, full, compact or json, one object per line for e.g. journald
+    /// (default: compact)
+    #[argh(option, default = "LogFormat::Compact")]
+    log_format: LogFormat,
+    /// where to read the settings from, locale1, the xorg.conf.d keyboard file, the XKB_DEFAULT_*
+    /// environment variables, or auto to try them in that order; only locale1 is watched for
+    /// changes (default: auto)
+    #[argh(option, default = "Source::Auto")]
+    source: Source,
     /// apply current settings and exit immediately
     #[argh(switch)]
     oneshot: bool,
+    /// keep trying to connect to sway for up to this many seconds at startup, in case it isn't
+    /// running yet (default: 0)
+    #[argh(option, default = "0")]
+    wait_for_sway: u64,
+    /// also apply the settings to matching input devices added later on
+    #[argh(switch)]
+    watch_devices: bool,
+    /// times to retry a sway command that failed (default: 3)
+    #[argh(option, default = "3")]
+    retry_count: u32,
+    /// milliseconds to wait before each retry (default: 100)
+    #[argh(option, default = "100")]
+    retry_delay_ms: u64,
+    /// what to do with settings locale1 has no value for, skip to leave them as sway has them, or
+    /// clear to apply the empty value (default: clear)
+    #[argh(option, default = "OnEmpty::Clear")]
+    on_empty: OnEmpty,
+    /// print the sway commands instead of running them, without connecting to sway
+    #[argh(switch)]
+    dry_run: bool,
 }
 
 fn main() -> anyhow::Result<()> {
-    let args: Args = argh::from_env();
+    let mut args: Args = argh::from_env();
+    if args.device.is_empty() {
+        args.device.push(args.device_type.unwrap_or(DeviceType::Keyboard).identifier());
+    }
 
-    tracing_subscriber::fmt()
+    // The devices given on the command line come first, followed by the ones only in the config.
+    let mut devices: Vec<Device> = args.device.iter().cloned().map(Device::new).collect();
+    if let Some(path) = &args.config {
+        for device in config::load(path)? {
+            match devices.iter_mut().find(|d| d.identifier == device.identifier) {
+                Some(d) => d.overrides = device.overrides,
+                None => devices.push(device),
+            }
+        }
+    }
+
+    if let Some(device) = devices.iter().find(|d| !is_valid_identifier(&d.identifier)) {
+        bail!("Invalid device identifier {:?}", device.identifier);
+    }
+    if args.dry_run && args.watch_devices {
+        bail!("--watch-devices needs a connection to sway, which --dry-run doesn't make");
+    }
+
+    // The commands of a dry run go to stdout, so keep the log out of their way.
+    let dry_run = args.dry_run;
+    let subscriber = tracing_subscriber::fmt()
         .with_max_level(args.log_level)
-        .compact()
-        .init();
-
-    let dbus = DbusLocale1::new().context("D-Bus")?;
-    let props = dbus.get_xkb_properties().context("D-Bus")?;
-
-    let mut sway = SwayIpc::new().context("Sway IPC")?;
-    info!("xkb({}): layout '{}' model '{}' variant '{}' options '{}'",
-          args.device, props.layout, props.model, props.variant, props.options);
-    sway.set_xkb_property(&args.device, XkbProperty::Layout, &props.layout);
-    sway.set_xkb_property(&args.device, XkbProperty::Model, &props.model);
-    sway.set_xkb_property(&args.device, XkbProperty::Variant, &props.variant);
-    sway.set_xkb_property(&args.device, XkbProperty::Options, &props.options);
-
-    if !args.oneshot {
-        while let Some(signal) = dbus.properties_changed_stream()?.next() {
-            let signal = signal.args()?;
-            if signal.interface_name() == "org.freedesktop.locale1" {
+        .with_writer(move || -> Box<dyn io::Write> {
+            if dry_run { Box::new(io::stderr()) } else { Box::new(io::stdout()) }
+        });
+    match args.log_format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+
+    zbus::block_on(run(&args, &devices))
+}
+
+async fn run(args: &Args, devices: &[Device]) -> anyhow::Result<()> {
+    let (props, mut changes) = initial_settings(args.source, args.oneshot).await?;
+
+    let sway = if args.dry_run {
+        SwayIpc::dry_run(args.on_empty)
+    } else {
+        let wait = Duration::from_secs(args.wait_for_sway);
+        SwayIpc::new(wait, args.retry_count, Duration::from_millis(args.retry_delay_ms), args.on_empty).await.context("Sway IPC")?
+    };
+    let mut applier = Applier::new(sway, devices);
+    applier.replace(props).await.context("Sway IPC")?;
+    #[cfg(feature = "systemd")]
+    systemd::notify_ready(&applier.props, &applier.sway.held_back())?;
+
+    let Some(changes) = &mut changes else { return Ok(()) };
+    let mut inputs = args.watch_devices.then(added_inputs);
+    #[cfg(feature = "systemd")]
+    let mut watchdog = if args.dry_run { None } else { systemd::watchdog_interval().map(Timer::interval) };
+    #[cfg(not(feature = "systemd"))]
+    let mut watchdog: Option<Timer> = None;
+
+    loop {
+        let wakeup = future::or(
+            async { Wakeup::Locale1(changes.next().await) },
+            future::or(
+                async {
+                    match &inputs {
+                        Some(inputs) => Wakeup::InputAdded(inputs.recv().await.ok()),
+                        None => future::pending().await,
+                    }
+                },
+                async {
+                    match &mut watchdog {
+                        Some(timer) => {
+                            timer.next().await;
+                            Wakeup::Watchdog
+                        },
+                        None => future::pending().await,
+                    }
+                },
+            ),
+        ).await;
+
+        let reconnected = match wakeup {
+            Wakeup::Locale1(None) => {
+                warn!("Lost the D-Bus connection, reconnecting");
+                let props;
+                (*changes, props) = reconnect_dbus().await;
+                info!("Reconnected to D-Bus, applying the current settings");
+                applier.replace(props).await.context("Sway IPC")?
+            },
+            Wakeup::Locale1(Some(signal)) => {
+                let signal = signal.args()?;
+                if signal.interface_name() != "org.freedesktop.locale1" {
+                    continue;
+                }
+
+                let mut batch = Vec::new();
                 for (name, value) in signal.changed_properties().iter() {
                     let value: String = value.try_into()?;
-                    info!("xkb({}): {} '{}'", args.device, name, value);
-                    match *name {
-                        "X11Layout"  => sway.set_xkb_property(&args.device, XkbProperty::Layout, &value),
-                        "X11Model"   => sway.set_xkb_property(&args.device, XkbProperty::Model, &value),
-                        "X11Variant" => sway.set_xkb_property(&args.device, XkbProperty::Variant, &value),
-                        "X11Options" => sway.set_xkb_property(&args.device, XkbProperty::Options, &value),
-                        _ => debug!(name, value=?value, "unhandled property"),
-                    }
+                    info!("xkb({}): {} '{}'", identifiers(devices), name, value);
+                    let prop = match *name {
+                        "X11Layout"  => XkbProperty::Layout,
+                        "X11Model"   => XkbProperty::Model,
+                        "X11Variant" => XkbProperty::Variant,
+                        "X11Options" => XkbProperty::Options,
+                        _ => {
+                            debug!(name, value=?value, "unhandled property");
+                            continue;
+                        },
+                    };
+                    batch.push((prop, value));
                 }
-            }
+                applier.change(&batch).await.context("Sway IPC")?
+            },
+            Wakeup::InputAdded(Some(input)) => applier.input_added(&input).await.context("Sway IPC")?,
+            // The subscription failed, which has been logged already, most likely as sway restarted.
+            // The new one has none of the settings, nor any new devices to watch for otherwise.
+            Wakeup::InputAdded(None) => {
+                warn!("Lost the sway input subscription, reconnecting");
+                match applier.reconnect().await {
+                    Ok(()) => inputs = Some(added_inputs()),
+                    Err(e) => {
+                        warn!(error = ?e, "Sway didn't come back, no longer watching for added devices");
+                        inputs = None;
+                    },
+                }
+                false
+            },
+            Wakeup::Watchdog => match applier.ping().await {
+                Ok(reconnected) => {
+                    #[cfg(feature = "systemd")]
+                    systemd::notify_watchdog();
+                    reconnected
+                },
+                Err(e) => {
+                    warn!(error = ?e, "Sway didn't answer the watchdog");
+                    false
+                },
+            },
+        };
+        if reconnected && args.watch_devices {
+            inputs = Some(added_inputs());
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+
+    #[test]
+    fn sanitizer_accepts_xkb_names() {
+        for value in ["", "us", "us,fi", ",nodeadkeys", "pc105", "grp:alt_shift_toggle,ctrl:nocaps", "lv3:ralt_switch", "compose:rctrl-altgr", "latin+level3(ralt_switch)", "custom.layout"] {
+            assert!(is_valid_xkb_value(value), "{:?}", value);
+        }
+        for identifier in ["*", "type:keyboard", "1:1:AT_Translated_Set_2_keyboard", "1133:49948:Logitech_USB_Keyboard"] {
+            assert!(is_valid_identifier(identifier), "{:?}", identifier);
+        }
+    }
+
+    #[test]
+    fn sanitizer_rejects_hostile_values() {
+        for value in ["us'; exec rm -rf ~; '", "us\"", "us`id`", "$(id)", "us;fi", "us\nexit", "us\0", "us\\", "us fi", "us\tfi",
+                      "{us}", "[us]", "us#fi", "ü", "us\u{202e}"] {
+            assert!(!is_valid_xkb_value(value), "{:?}", value);
+        }
+        for identifier in ["", " ", "type:keyboard; exit", "my keyboard", "'*'", "\"*\"", "a,b", "[class=.*]", "{*}", "kbd\n", "kbd\0", "kbd\u{a0}"] {
+            assert!(!is_valid_identifier(identifier), "{:?}", identifier);
+        }
+    }
+
+    #[test]
+    fn input_command_quotes_values() {
+        assert_eq!(input_command("type:keyboard", XkbProperty::Layout, "us,fi").unwrap(), "input type:keyboard xkb_layout 'us,fi'");
+        assert_eq!(input_command("*", XkbProperty::Variant, ",nodeadkeys").unwrap(), "input * xkb_variant ',nodeadkeys'");
+        assert_eq!(input_command("1:1:AT_Translated_Set_2_keyboard", XkbProperty::Options, "grp:alt_shift_toggle,ctrl:nocaps").unwrap(),
+                   "input 1:1:AT_Translated_Set_2_keyboard xkb_options 'grp:alt_shift_toggle,ctrl:nocaps'");
+        assert_eq!(input_command("type:keyboard", XkbProperty::Model, "").unwrap(), "input type:keyboard xkb_model ''");
+    }
+
+    #[test]
+    fn input_command_rejects_breaking_values() {
+        for value in ["us fi", "us'", "'us'", "us\"", "de nodeadkeys", "ctrl:nocaps, grp:alt_shift_toggle"] {
+            assert!(input_command("type:keyboard", XkbProperty::Layout, value).is_err(), "{:?}", value);
+            assert!(input_command("type:keyboard", XkbProperty::Variant, value).is_err(), "{:?}", value);
+            assert!(input_command("type:keyboard", XkbProperty::Options, value).is_err(), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn apply_batch_skips_only_the_invalid_value() {
+        let devices = [Device::new("type:keyboard".to_owned())];
+        let mut sway = SwayIpc::dry_run(OnEmpty::Clear);
+        let batch = [(XkbProperty::Layout, "us"), (XkbProperty::Variant, "x' y")];
+        future::block_on(sway.apply_batch(&devices, &batch)).unwrap();
+        assert_eq!(sway.applied.get(&("type:keyboard".to_owned(), XkbProperty::Layout)).map(String::as_str), Some("us"));
+        assert!(!sway.applied.contains_key(&("type:keyboard".to_owned(), XkbProperty::Variant)));
+        assert_eq!(sway.held_back(), ["xkb_variant of type:keyboard"]);
+        // Until a valid value comes along.
+        future::block_on(sway.apply_batch(&devices, &[(XkbProperty::Variant, "nodeadkeys")])).unwrap();
+        assert!(sway.held_back().is_empty());
+    }
+
+    fn applied(applier: &Applier, prop: XkbProperty) -> Option<String> {
+        applier.sway.applied.get(&("type:keyboard".to_owned(), prop)).cloned()
+    }
+
+    #[test]
+    fn applier_applies_changes() {
+        let devices = [Device::new("type:keyboard".to_owned())];
+        let mut applier = Applier::new(SwayIpc::dry_run(OnEmpty::Clear), &devices);
+        let props = XkbProperties { layout: "de".to_owned(), variant: "nodeadkeys".to_owned(), ..Default::default() };
+        future::block_on(async {
+            applier.replace(props).await.unwrap();
+            assert_eq!(applied(&applier, XkbProperty::Layout).as_deref(), Some("de"));
+            assert_eq!(applied(&applier, XkbProperty::Variant).as_deref(), Some("nodeadkeys"));
+            assert_eq!(applied(&applier, XkbProperty::Options).as_deref(), Some(""));
+
+            applier.change(&[(XkbProperty::Layout, "us".to_owned()), (XkbProperty::Variant, String::new())]).await.unwrap();
+            assert_eq!(applied(&applier, XkbProperty::Layout).as_deref(), Some("us"));
+            assert_eq!(applied(&applier, XkbProperty::Variant).as_deref(), Some(""));
+            assert_eq!(applier.props.layout, "us");
+        });
+    }
+
+    #[test]
+    fn applier_skips_empty_values() {
+        let devices = [Device::new("type:keyboard".to_owned())];
+        let mut applier = Applier::new(SwayIpc::dry_run(OnEmpty::Skip), &devices);
+        let props = XkbProperties { layout: "xx".to_owned(), ..Default::default() };
+        future::block_on(async {
+            applier.replace(props).await.unwrap();
+            assert_eq!(applied(&applier, XkbProperty::Layout).as_deref(), Some("xx"));
+            assert_eq!(applied(&applier, XkbProperty::Variant), None);
+        });
+    }
 }
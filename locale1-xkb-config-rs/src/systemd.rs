@@ -0,0 +1,44 @@
+use std::env;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::XkbProperties;
+
+pub use sd_notify::sd_notify;
+
+/// The settings last applied for `systemctl status`, degraded when some were `held_back`.
+fn status(props: &XkbProperties, held_back: &[String]) -> String {
+    let applied = format!("layout '{}' model '{}' variant '{}' options '{}'",
+                          props.layout, props.model, props.variant, props.options);
+    if held_back.is_empty() {
+        format!("STATUS=Applied {}", applied)
+    } else {
+        format!("STATUS=Degraded, held back {} of {}", held_back.join(", "), applied)
+    }
+}
+
+/// Shows the settings last applied in `systemctl status`.
+pub fn notify_status(props: &XkbProperties, held_back: &[String]) {
+    if let Err(e) = sd_notify(&status(props, held_back)) {
+        warn!(error = ?e, "Notifying systemd of the status failed");
+    }
+}
+
+/// Tells systemd the settings were first applied, along with the status, degraded or not.
+pub fn notify_ready(props: &XkbProperties, held_back: &[String]) -> anyhow::Result<()> {
+    sd_notify(&format!("READY=1\n{}", status(props, held_back)))?;
+    Ok(())
+}
+
+/// Half of WatchdogSec, how often to check in with systemd, or None without it set.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Tells systemd we're alive, once sway answered.
+pub fn notify_watchdog() {
+    if let Err(e) = sd_notify("WATCHDOG=1") {
+        warn!(error = ?e, "Notifying the watchdog failed");
+    }
+}
@@ -0,0 +1,49 @@
+#![forbid(unsafe_code)]
+//! sd_notify(3) for the systemd features of the other crates, without linking libsystemd.
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Sends `state` to the service manager, see sd_notify(3). Does nothing when not run by systemd.
+pub fn sd_notify(state: &str) -> io::Result<()> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else { return Ok(()) };
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Both kinds of address in one test, as NOTIFY_SOCKET is process wide.
+    #[test]
+    fn notifies_socket() {
+        let mut buf = [0; 64];
+
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(sd_notify("READY=1").is_ok());
+
+        let path = env::temp_dir().join(format!("sd-notify-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+        sd_notify("READY=1").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        let name = format!("sd-notify-test-{}", std::process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        env::set_var("NOTIFY_SOCKET", format!("@{}", name));
+        sd_notify("WATCHDOG=1").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+        env::remove_var("NOTIFY_SOCKET");
+    }
+}
@@ -1,18 +1,193 @@
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::net::Shutdown;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{Read, Write};
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::thread;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicI64, Ordering};
-use swayipc::{Connection, EventType};
+use std::{process, thread};
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use swayipc::{Connection, EventType, NodeType};
 use swayipc::{Event, WindowChange};
 use anyhow::{Context, Result, anyhow, bail};
+use argh::{EarlyExit, FromArgs};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal::{SigSet, Signal};
+use serde_json::json;
+use zbus::zvariant::Value;
 
-/// Panics: on IO error.
-fn cmd_server(socket_filename: PathBuf, prev_window: Arc<AtomicI64>) {
+#[cfg(feature = "systemd")]
+mod systemd;
+
+/// Shows a desktop notification through the org.freedesktop.Notifications D-Bus interface.
+fn notify(summary: &str) -> Result<()> {
+    let conn = zbus::blocking::Connection::session()?;
+    conn.call_method(
+        Some("org.freedesktop.Notifications"),
+        "/org/freedesktop/Notifications",
+        Some("org.freedesktop.Notifications"),
+        "Notify",
+        &(env!("CARGO_PKG_NAME"), 0_u32, "", summary, "", Vec::<&str>::new(), HashMap::<&str, Value>::new(), -1_i32),
+    )?;
+    Ok(())
+}
+
+/// Longest message the socket listener accepts.
+const MAX_MESSAGE_LEN: u64 = 64;
+
+/// How long the socket listener waits for a client to send its message, so that one that never
+/// finishes doesn't hold up the others.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a client waits for the listener, which may be waiting on sway to answer.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many windows to remember in either direction.
+const HISTORY_LEN: usize = 64;
+
+/// Focus history, with the windows to go back to, and the ones gone back from to go forward to.
+#[derive(Default)]
+struct History {
+    curr: Option<i64>,
+    back: Vec<i64>,
+    forward: Vec<i64>,
+    /// The window being focused by going back or forward, whose focus event shouldn't count as a
+    /// new focus.
+    expected: Option<i64>,
+    /// Names of the windows seen so far, for listing.
+    names: HashMap<i64, String>,
+    /// Windows moved to another workspace since they were last focused, whose workspace is switched
+    /// to before focusing them.
+    moved: HashSet<i64>,
+    /// The workspace each window was on when last focused, to tell moves to another workspace from
+    /// moves within it.
+    workspaces: HashMap<i64, String>,
+    /// Whether going back goes further back every time, with the windows gone back from to go
+    /// forward to, rather than toggling between the two most recent windows.
+    back_forward: bool,
+}
+
+impl History {
+    fn push(stack: &mut Vec<i64>, id: i64) {
+        if stack.len() >= HISTORY_LEN {
+            stack.remove(0);
+        }
+        stack.push(id);
+    }
+
+    fn focused(&mut self, id: i64) {
+        self.moved.remove(&id);
+        if self.expected.take() == Some(id) || self.curr == Some(id) {
+            return;
+        }
+        if let Some(curr) = self.curr {
+            Self::push(&mut self.back, curr);
+        }
+        self.curr = Some(id);
+        self.forward.clear();
+    }
+
+    /// Records window `id` as moved, now being on `workspace`, or somewhere unknown with None.
+    fn moved_to(&mut self, id: i64, workspace: Option<String>) {
+        if workspace.is_some() && workspace.as_ref() == self.workspaces.get(&id) {
+            self.moved.remove(&id);
+        } else {
+            self.moved.insert(id);
+        }
+    }
+
+    fn named(&mut self, id: i64, name: Option<String>) {
+        match name {
+            Some(name) => self.names.insert(id, name),
+            None => self.names.remove(&id),
+        };
+    }
+
+    fn closed(&mut self, id: i64) {
+        self.names.remove(&id);
+        self.moved.remove(&id);
+        self.workspaces.remove(&id);
+        self.back.retain(|&w| w != id);
+        self.forward.retain(|&w| w != id);
+        if self.curr == Some(id) {
+            self.curr = None;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.back.clear();
+        self.forward.clear();
+    }
+
+    /// Forgets the windows `ids`, other than the current one.
+    fn forget(&mut self, ids: &[i64]) {
+        self.back.retain(|w| !ids.contains(w));
+        self.forward.retain(|w| !ids.contains(w));
+    }
+
+    /// The windows in the history from the oldest to the newest, and whether each is the current one.
+    fn entries(&self) -> Vec<(i64, bool)> {
+        self.back.iter().map(|&id| (id, false))
+            .chain(self.curr.map(|id| (id, true)))
+            .chain(self.forward.iter().rev().map(|&id| (id, false)))
+            .collect()
+    }
+
+    /// Formats the history as "<id> <name>" lines, or as a JSON array with `json`.
+    fn list(&self, json: bool) -> String {
+        let name = |id| self.names.get(&id).map(String::as_str).unwrap_or_default();
+        if json {
+            let entries: Vec<_> = self.entries().into_iter()
+                .map(|(id, current)| json!({ "id": id, "name": name(id), "current": current }))
+                .collect();
+            serde_json::Value::from(entries).to_string()
+        } else {
+            self.entries().into_iter().map(|(id, _)| format!("{} {}\n", id, name(id))).collect()
+        }
+    }
+
+    /// Moves one step back, or forward with `forward`, if `focus` manages to focus the window there,
+    /// returning whether it did.
+    fn go(&mut self, forward: bool, focus: impl FnOnce(&Self, i64) -> bool) -> bool {
+        let target = if forward { self.forward.last() } else { self.back.last() };
+        let focused = target.is_some_and(|&id| focus(self, id));
+        if focused {
+            self.step(forward);
+        }
+        focused
+    }
+
+    /// Moves one step back, or forward with `forward`, returning the window to focus. Without
+    /// `back_forward`, the window gone back from is the one to go back to next, so going back twice
+    /// comes back to where it started, and there is nothing to go forward to.
+    fn step(&mut self, forward: bool) -> Option<i64> {
+        let (from, to) = match (forward, self.back_forward) {
+            (true, _) => (&mut self.forward, &mut self.back),
+            (false, true) => (&mut self.back, &mut self.forward),
+            (false, false) => {
+                let target = self.back.pop()?;
+                if let Some(curr) = self.curr {
+                    Self::push(&mut self.back, curr);
+                }
+                self.curr = Some(target);
+                self.expected = Some(target);
+                return Some(target);
+            },
+        };
+        let target = from.pop()?;
+        if let Some(curr) = self.curr {
+            Self::push(to, curr);
+        }
+        self.curr = Some(target);
+        self.expected = Some(target);
+        Some(target)
+    }
+}
+
+/// Panics: on IO error setting up the socket. Clients that fail to send their message are skipped.
+fn cmd_server(socket_filename: PathBuf, history: Arc<Mutex<History>>, notify_missing: bool, raise_floating: bool) {
     if socket_filename.exists() {
         fs::remove_file(&socket_filename).expect("Unable to remove old socket file");
     }
@@ -20,54 +195,318 @@ fn cmd_server(socket_filename: PathBuf, prev_window: Arc<AtomicI64>) {
     let listener = UnixListener::bind(&socket_filename).expect("Could not bind socket");
     let mut conn = Connection::new().expect("Could not obtain a connection to sway IPC socket");
 
-    let mut buf: [u8; 4] = [0; 4];
-    for stream in listener.incoming() {
-        if let Ok(mut stream) = stream {
-            stream.read_exact(&mut buf).expect("Failed to read socket stream");
-            match &buf {
-                b"prev" => {
-                    conn.run_command(format!("[con_id={}] focus", prev_window.load(Ordering::Acquire)))
-                        .expect("Running sway IPC command failed");
-                    let _ = stream.shutdown(Shutdown::Both);
-                },
-                _ => {
-                    let _ = stream.shutdown(Shutdown::Both);
-                    if socket_filename.exists() {
-                        fs::remove_file(&socket_filename).expect("Unable to remove old socket file");
+    for mut stream in listener.incoming().flatten() {
+        let msg = match read_message(&mut stream) {
+            Ok(msg) => msg,
+            Err(e) => {
+                eprintln!("Failed to read socket stream: {:?}", e);
+                continue;
+            },
+        };
+        match msg.as_str() {
+            "prev" | "next" => {
+                // Held until the focus has moved, so that the history moves only when the focus did,
+                // and the focus event is handled only once the history expects it.
+                let mut history = history.lock().expect("History lock poisoned");
+                let focused = history.go(msg == "next", |history, id| {
+                    // Where a moved window ended up is looked up only now, as it may have moved again.
+                    let workspace = if history.moved.contains(&id) {
+                        window_workspace(&mut conn, id).unwrap_or_else(|e| {
+                            eprintln!("Could not find the workspace of {}: {:?}", id, e);
+                            None
+                        })
+                    } else {
+                        None
+                    };
+                    let mut cmd = match workspace {
+                        Some(name) => format!("{}; ", workspace_command(&name)),
+                        None => String::new(),
+                    };
+                    let toplevel = if raise_floating {
+                        floating_toplevel(&mut conn, id).unwrap_or_else(|e| {
+                            eprintln!("Could not find out whether {} is floating: {:?}", id, e);
+                            None
+                        })
+                    } else {
+                        None
+                    };
+                    // Sway has no raise command, but brings the floating container it focuses to
+                    // the front.
+                    if let Some(toplevel) = toplevel {
+                        cmd.push_str(&format!("[con_id={}] focus; ", toplevel));
                     }
-                    return;
+                    cmd.push_str(&format!("[con_id={}] focus", id));
+                    match conn.run_command(cmd) {
+                        Ok(replies) => replies.iter().all(|r| r.is_ok()),
+                        Err(e) => {
+                            eprintln!("Running sway IPC command failed: {:?}", e);
+                            false
+                        },
+                    }
+                });
+                drop(history);
+                if !focused && notify_missing {
+                    let summary = if msg == "next" { "No next window" } else { "No previous window" };
+                    if let Err(e) = notify(summary) {
+                        eprintln!("Sending a notification failed: {:?}", e);
+                    }
+                }
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            "list" | "list-json" => {
+                let list = history.lock().expect("History lock poisoned").list(msg == "list-json");
+                let _ = stream.write_all(list.as_bytes());
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            "ping" => {
+                let _ = stream.write_all(b"pong");
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            "status" => {
+                let len = history.lock().expect("History lock poisoned").entries().len();
+                let _ = stream.write_all(len.to_string().as_bytes());
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            "clear" => {
+                history.lock().expect("History lock poisoned").clear();
+                let _ = stream.write_all(b"ok");
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            "clear-workspace" => {
+                match focused_workspace_windows(&mut conn) {
+                    Ok(ids) => {
+                        history.lock().expect("History lock poisoned").forget(&ids);
+                        let _ = stream.write_all(b"ok");
+                    },
+                    Err(e) => eprintln!("Could not find the focused workspace: {:?}", e),
                 }
+                let _ = stream.shutdown(Shutdown::Both);
+            },
+            _ => {
+                let _ = stream.shutdown(Shutdown::Both);
+                if socket_filename.exists() {
+                    fs::remove_file(&socket_filename).expect("Unable to remove old socket file");
+                }
+                return;
             }
         }
     }
 }
 
+/// Reads the single message a client sends before shutting down its end, giving up after
+/// READ_TIMEOUT.
+fn read_message(stream: &mut UnixStream) -> std::io::Result<String> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(READ_TIMEOUT))?;
+    let mut msg = String::new();
+    stream.take(MAX_MESSAGE_LEN).read_to_string(&mut msg)?;
+    Ok(msg)
+}
+
+/// Sends `msg` to the socket listener, returning its response.
+fn send(socket_filename: &Path, msg: &str) -> Result<String> {
+    let mut stream = UnixStream::connect(socket_filename)
+        .with_context(|| format!("Unable to connect {} socket.", env!("CARGO_PKG_NAME")))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.write_all(msg.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// Checks that the socket listener is responsive.
+fn ping(socket_filename: &Path) -> Result<()> {
+    match send(socket_filename, "ping")?.as_str() {
+        "pong" => Ok(()),
+        r => bail!("Unexpected response to ping: {:?}", r),
+    }
+}
+
 fn focused_window(conn: &mut Connection) -> Result<i64> {
     let mut node = conn.get_tree()?;
     while !node.focused {
         let fid = node.focus.into_iter().next().ok_or_else(|| anyhow!("Sway tree has no focused nodes."))?;
-        node = node.nodes.into_iter().chain(node.floating_nodes.into_iter())
+        node = node.nodes.into_iter().chain(node.floating_nodes)
             .find(|n| n.id == fid)
             .ok_or_else(|| anyhow!("Focused node not found in the nodes lists."))?;
     }
     Ok(node.id)
 }
 
-fn listen(socket_filename: PathBuf) -> Result<()> {
+/// Ids of all the windows on the focused workspace.
+fn focused_workspace_windows(conn: &mut Connection) -> Result<Vec<i64>> {
+    fn leaves(n: &swayipc::Node, ids: &mut Vec<i64>) {
+        if n.nodes.is_empty() && n.floating_nodes.is_empty() {
+            ids.push(n.id);
+        }
+        for c in n.nodes.iter().chain(&n.floating_nodes) {
+            leaves(c, ids);
+        }
+    }
+
+    let mut node = conn.get_tree()?;
+    while node.node_type != NodeType::Workspace {
+        let fid = node.focus.into_iter().next().ok_or_else(|| anyhow!("Sway tree has no focused nodes."))?;
+        node = node.nodes.into_iter().find(|n| n.id == fid)
+            .ok_or_else(|| anyhow!("Focused node not found in the nodes lists."))?;
+    }
+    let mut ids = Vec::new();
+    leaves(&node, &mut ids);
+    Ok(ids)
+}
+
+/// Name of the workspace window `id` is on in `tree`, or None if it isn't on a regular workspace,
+/// e.g. when it's in the scratchpad.
+fn tree_workspace(tree: &swayipc::Node, id: i64) -> Result<Option<String>> {
+    fn find(n: &swayipc::Node, id: i64, workspace: Option<&str>) -> Option<Option<String>> {
+        let workspace = if n.node_type == NodeType::Workspace { n.name.as_deref() } else { workspace };
+        if n.id == id {
+            return Some(workspace.filter(|w| !w.starts_with("__i3")).map(str::to_owned));
+        }
+        n.nodes.iter().chain(&n.floating_nodes).find_map(|c| find(c, id, workspace))
+    }
+
+    find(tree, id, None).ok_or_else(|| anyhow!("Window {} not found in the sway tree.", id))
+}
+
+/// Name of the workspace window `id` is on, like `tree_workspace`.
+fn window_workspace(conn: &mut Connection, id: i64) -> Result<Option<String>> {
+    tree_workspace(&conn.get_tree()?, id)
+}
+
+/// Switches to the workspace `name`, staying there even with workspace_auto_back_and_forth.
+fn workspace_command(name: &str) -> String {
+    format!("workspace --no-auto-back-and-forth \"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Id of the floating container window `id` is in, the window itself if it floats on its own, or
+/// None if it's tiled.
+fn floating_toplevel(conn: &mut Connection, id: i64) -> Result<Option<i64>> {
+    fn contains(n: &swayipc::Node, id: i64) -> bool {
+        n.id == id || n.nodes.iter().chain(&n.floating_nodes).any(|c| contains(c, id))
+    }
+    fn find(n: &swayipc::Node, id: i64) -> Option<Option<i64>> {
+        if n.node_type == NodeType::Workspace {
+            if let Some(f) = n.floating_nodes.iter().find(|f| contains(f, id)) {
+                return Some(Some(f.id));
+            }
+            return contains(n, id).then_some(None);
+        }
+        n.nodes.iter().find_map(|c| find(c, id))
+    }
+
+    find(&conn.get_tree()?, id).ok_or_else(|| anyhow!("Window {} not found in the sway tree.", id))
+}
+
+/// Takes an exclusive lock on `lock_filename` for as long as the returned file is kept open, so
+/// that only one listener runs at a time.
+fn lock_listener(lock_filename: &Path) -> Result<Flock<fs::File>> {
+    let file = fs::File::create(lock_filename)
+        .with_context(|| format!("Unable to open lock file {}", lock_filename.display()))?;
+    match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(lock) => Ok(lock),
+        Err((_, Errno::EWOULDBLOCK)) => bail!("Daemon already running."),
+        Err((_, e)) => Err(e).context("Unable to lock the lock file"),
+    }
+}
+
+/// The pid of the listener written to a file, removed again when dropped.
+struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Writes the pid to `path`, unless the file names a listener that's still running.
+    fn create(path: PathBuf) -> Result<Self> {
+        if let Some(pid) = fs::read_to_string(&path).ok().and_then(|s| s.trim().parse::<u32>().ok()) {
+            if is_listener(pid) {
+                bail!("Daemon already running with pid {}, according to {}.", pid, path.display());
+            }
+        }
+        fs::write(&path, format!("{}\n", process::id()))
+            .with_context(|| format!("Unable to write pid file {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+/// Whether `pid` is a running process of the same executable, rather than some other process that
+/// got the pid of a listener that has since exited. Goes by the name in /proc, which unlike the
+/// executable's path survives an upgrade of the binary.
+fn is_listener(pid: u32) -> bool {
+    let comm = |pid: &str| fs::read_to_string(Path::new("/proc").join(pid).join("comm"));
+    matches!((comm(&pid.to_string()), comm("self")), (Ok(theirs), Ok(ours)) if theirs == ours)
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Removes `pid_file` and exits on SIGTERM or SIGINT. The signals are blocked in this and all the
+/// threads spawned after, and waited for in a thread of their own, so no signal handler is needed.
+fn remove_on_signal(pid_file: PathBuf) -> Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.thread_block().context("Unable to block SIGTERM")?;
+
+    thread::spawn(move || {
+        let Ok(signal) = signals.wait() else { return };
+        let _ = fs::remove_file(&pid_file);
+        process::exit(128 + signal as i32);
+    });
+    Ok(())
+}
+
+/// Fails if a listener answers on `socket_filename`. A daemon that doesn't take the lock may still
+/// be listening, in which case its socket is left alone. Only a socket nobody answers on is stale
+/// and gets replaced.
+fn check_stale(socket_filename: &Path) -> Result<()> {
+    if socket_filename.exists() && ping(socket_filename).is_ok() {
+        bail!("Daemon already running, listening on {}.", socket_filename.display());
+    }
+    Ok(())
+}
+
+fn listen(socket_filename: PathBuf, notify_missing: bool, raise_floating: bool, back_forward: bool, pid_file: Option<PathBuf>) -> Result<()> {
+    // Held until the listener exits, the lock is released along with the file.
+    let _lock = lock_listener(&socket_filename.with_extension("lock"))?;
+    check_stale(&socket_filename)?;
+    // Before any other thread is spawned, for them to inherit the blocked signals.
+    let _pid_file = match pid_file {
+        Some(path) => {
+            let pid_file = PidFile::create(path)?;
+            remove_on_signal(pid_file.0.clone())?;
+            Some(pid_file)
+        },
+        None => None,
+    };
     let mut conn = Connection::new()?;
-    let prev_window = Arc::new(AtomicI64::new(-1));
-    let mut curr_window = focused_window(&mut conn).unwrap_or(-1);
+    // For looking up workspaces while conn is taken up by the events.
+    let mut query = Connection::new()?;
+    let history = Arc::new(Mutex::new(History {
+        curr: focused_window(&mut conn).ok(),
+        back_forward,
+        ..History::default()
+    }));
 
     // Spawn unix socket listener.
-    let prevc = Arc::clone(&prev_window);
+    let historyc = Arc::clone(&history);
     let socket_filenamec = socket_filename.clone();
-    let listener_handle = thread::spawn(move || cmd_server(socket_filenamec, prevc));
+    let listener_handle = thread::spawn(move || cmd_server(socket_filenamec, historyc, notify_missing, raise_floating));
+
+    #[cfg(feature = "systemd")]
+    {
+        systemd::spawn_watchdog(socket_filename.clone());
+        systemd::sd_notify("READY=1")?;
+    }
 
     // Subscribe to sway window events.
-    let events = conn.subscribe(&[EventType::Window, EventType::Shutdown])?;
+    let events = conn.subscribe([EventType::Window, EventType::Shutdown])?;
     for event in events {
         // bail if
-        if Arc::strong_count(&prev_window) < 2 {
+        if Arc::strong_count(&history) < 2 {
             bail!("{} socket listener closed unexpectedly.", env!("CARGO_PKG_NAME"));
         }
 
@@ -75,15 +514,28 @@ fn listen(socket_filename: PathBuf) -> Result<()> {
             Event::Window(e) => {
                 match e.change {
                     WindowChange::Focus => {
-                        prev_window.store(curr_window, Ordering::Release);
-                        curr_window = e.container.id;
+                        // The window's own workspace, as the focus may well have gone elsewhere by now.
+                        let workspace = window_workspace(&mut query, e.container.id).map_err(|e| eprintln!("{:?}", e));
+                        let mut history = history.lock().expect("History lock poisoned");
+                        history.named(e.container.id, e.container.name);
+                        history.focused(e.container.id);
+                        match workspace {
+                            Ok(Some(workspace)) => history.workspaces.insert(e.container.id, workspace),
+                            Ok(None) => history.workspaces.remove(&e.container.id),
+                            // Logged above, most likely closed already.
+                            Err(()) => None,
+                        };
                     },
-                    WindowChange::Close => {
-                        let _ = prev_window.compare_exchange(e.container.id, -1,
-                                                             Ordering::Relaxed, Ordering::Relaxed);
-                        if e.container.id == curr_window {
-                            curr_window = -1;
-                        }
+                    WindowChange::Title => history.lock().expect("History lock poisoned").named(e.container.id, e.container.name),
+                    WindowChange::Close => history.lock().expect("History lock poisoned").closed(e.container.id),
+                    // Moves within a workspace, e.g. swapping windows around, don't need the workspace
+                    // switched to.
+                    WindowChange::Move => {
+                        let workspace = window_workspace(&mut query, e.container.id).unwrap_or_else(|e| {
+                            eprintln!("{:?}", e);
+                            None
+                        });
+                        history.lock().expect("History lock poisoned").moved_to(e.container.id, workspace);
                     },
                     _ => {}
                 }
@@ -100,14 +552,370 @@ fn listen(socket_filename: PathBuf) -> Result<()> {
     Ok(())
 }
 
+/// Path of the listener's socket in `runtime_dir`.
+fn socket_path(runtime_dir: impl Into<PathBuf>) -> PathBuf {
+    runtime_dir.into().join("sway-focus-switcheroo.socket")
+}
+
 fn main() -> Result<()> {
-    let socket_filename = PathBuf::from(format!("{}/sway-focus-switcheroo.socket", env::var("XDG_RUNTIME_DIR")?));
-
-    if env::args().nth(1).is_some() {
-        listen(socket_filename)
-    } else {
-        UnixStream::connect(socket_filename)
-            .with_context(|| format!("Unable to connect {} socket.", env!("CARGO_PKG_NAME")))?
-          .write_all(b"prev").map_err(|e| e.into())
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set."))?;
+    let socket_filename = socket_path(runtime_dir);
+
+    match env::args().nth(1).as_deref() {
+        Some("listen") => listen_with_args(socket_filename, "listen"),
+        None | Some("prev") => send(&socket_filename, "prev").map(drop),
+        Some("next") => send(&socket_filename, "next").map(drop),
+        Some("ping") => {
+            ping(&socket_filename)?;
+            println!("pong");
+            Ok(())
+        },
+        Some("status") => {
+            let response = send(&socket_filename, "status")?;
+            let len: usize = response.parse().with_context(|| format!("Unexpected response to status: {:?}", response))?;
+            println!("Listening on {}, {} window{} in history", socket_filename.display(), len, if len == 1 { "" } else { "s" });
+            Ok(())
+        },
+        Some("list") => {
+            let msg = if env::args().skip(2).any(|a| a == "--json") { "list-json" } else { "list" };
+            print!("{}", send(&socket_filename, msg)?);
+            Ok(())
+        },
+        Some(msg @ ("clear" | "clear-workspace")) => match send(&socket_filename, msg)?.as_str() {
+            "ok" => Ok(()),
+            r => bail!("Unexpected response to {}: {:?}", msg, r),
+        },
+        // Older versions started the listener when given any argument at all, which configs may
+        // still rely on.
+        Some(arg) => {
+            eprintln!("Unknown command '{}', starting the listener like older versions did. Use listen instead.", arg);
+            listen_with_args(socket_filename, arg)
+        },
+    }
+}
+
+/// Start the listener that keeps track of the focus history.
+#[derive(FromArgs)]
+struct ListenArgs {
+    /// show a desktop notification when there is no window to switch to
+    #[argh(switch)]
+    notify: bool,
+    /// bring a floating window to the front of the others when switching to it
+    #[argh(switch)]
+    raise_floating: bool,
+    /// go back through the history one window at a time with prev, and forward again with next,
+    /// rather than toggling between the two most recent windows
+    #[argh(switch)]
+    back_forward: bool,
+    /// write the listener's pid to this file, removed again on exit
+    #[argh(option, arg_name = "path", from_str_fn(pid_file_from_str))]
+    pid_file: Option<PathBuf>,
+}
+
+/// A path for --pid-file, which mustn't be another option, e.g. with the path left out.
+fn pid_file_from_str(value: &str) -> Result<PathBuf, String> {
+    if value.starts_with('-') {
+        return Err(format!("expected a path, not '{}'", value));
+    }
+    Ok(PathBuf::from(value))
+}
+
+/// Starts the listener with the options following `command`, exiting on unknown ones.
+fn listen_with_args(socket_filename: PathBuf, command: &str) -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let ListenArgs { notify, raise_floating, back_forward, pid_file } =
+        match ListenArgs::from_args(&[args[0], command], &args[2..]) {
+            Ok(args) => args,
+            Err(EarlyExit { output, status }) => match status {
+                Ok(()) => {
+                    println!("{}", output);
+                    process::exit(0);
+                },
+                Err(()) => {
+                    eprintln!("{}", output);
+                    process::exit(1);
+                },
+            },
+        };
+    listen(socket_filename, notify, raise_floating, back_forward, pid_file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history of focusing windows `ids` in order.
+    fn history(back_forward: bool, ids: &[i64]) -> History {
+        let mut history = History { back_forward, ..History::default() };
+        for &id in ids {
+            history.focused(id);
+        }
+        history
+    }
+
+    /// Steps back or forward, and has sway report the focus moving there.
+    fn step(history: &mut History, forward: bool) -> Option<i64> {
+        let target = history.step(forward);
+        if let Some(id) = target {
+            history.focused(id);
+        }
+        target
+    }
+
+    fn ids(history: &History) -> Vec<i64> {
+        history.entries().into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn prev_toggles() {
+        let mut history = history(false, &[1, 2, 3]);
+        assert_eq!(step(&mut history, false), Some(2));
+        assert_eq!(step(&mut history, false), Some(3));
+        assert_eq!(step(&mut history, false), Some(2));
+        assert_eq!(step(&mut history, true), None);
+        assert_eq!(history.curr, Some(2));
+        assert_eq!(ids(&history), [1, 3, 2]);
+    }
+
+    #[test]
+    fn back_forward() {
+        let mut history = history(true, &[1, 2, 3]);
+        assert_eq!(step(&mut history, false), Some(2));
+        assert_eq!(step(&mut history, false), Some(1));
+        assert_eq!(step(&mut history, false), None);
+        assert_eq!(step(&mut history, true), Some(2));
+        assert_eq!(step(&mut history, true), Some(3));
+        assert_eq!(step(&mut history, true), None);
+        // Going back and then somewhere else starts over from there.
+        assert_eq!(step(&mut history, false), Some(2));
+        history.focused(4);
+        assert_eq!(step(&mut history, true), None);
+        assert_eq!(ids(&history), [1, 2, 4]);
+    }
+
+    #[test]
+    fn failed_focus_keeps_history() {
+        for back_forward in [false, true] {
+            let mut history = history(back_forward, &[1, 2, 3]);
+            assert!(!history.go(false, |_, id| { assert_eq!(id, 2); false }));
+            assert_eq!((history.curr, history.expected), (Some(3), None));
+            assert_eq!(ids(&history), [1, 2, 3]);
+            // The next focus counts as a new one.
+            history.focused(4);
+            assert_eq!(ids(&history), [1, 2, 3, 4]);
+
+            assert!(history.go(false, |_, id| id == 3));
+            assert_eq!((history.curr, history.expected), (Some(3), Some(3)));
+            let forward: &[i64] = if back_forward { &[4] } else { &[] };
+            assert_eq!(history.forward, forward);
+        }
+    }
+
+    #[test]
+    fn moved_to_another_workspace() {
+        let mut history = history(false, &[1, 2]);
+        history.workspaces.extend([(1, "1".to_owned()), (2, "2".to_owned())]);
+        history.moved_to(1, Some("1".to_owned()));
+        assert!(history.moved.is_empty());
+        history.moved_to(1, Some("3".to_owned()));
+        history.moved_to(2, None);
+        assert_eq!(history.moved, HashSet::from([1, 2]));
+        // And back again.
+        history.moved_to(1, Some("1".to_owned()));
+        assert_eq!(history.moved, HashSet::from([2]));
+        // Unknown to begin with.
+        history.moved_to(3, Some("1".to_owned()));
+        assert!(history.moved.contains(&3));
+        history.closed(2);
+        assert!(!history.moved.contains(&2) && !history.workspaces.contains_key(&2));
+    }
+
+    /// A node of the sway tree as get_tree reports it, with the fields that don't matter here
+    /// filled in.
+    fn node(id: i64, node_type: &str, name: &str, nodes: Vec<serde_json::Value>) -> serde_json::Value {
+        let rect = json!({ "x": 0, "y": 0, "width": 0, "height": 0 });
+        json!({
+            "id": id, "name": name, "type": node_type, "border": "none", "current_border_width": 0,
+            "layout": "splith", "percent": null, "rect": rect, "window_rect": rect, "deco_rect": rect,
+            "geometry": rect, "urgent": false, "focused": false, "focus": [], "sticky": false,
+            "nodes": nodes, "floating_nodes": [],
+        })
+    }
+
+    #[test]
+    fn workspace_of_the_window_itself() {
+        let output = |id, workspaces| node(id, "output", "out", workspaces);
+        let tree = node(1, "root", "root", vec![
+            output(2, vec![node(3, "workspace", "__i3_scratch", vec![node(30, "con", "scratch", vec![])])]),
+            // Focused or not doesn't matter, only where the window is.
+            output(4, vec![
+                node(5, "workspace", "1", vec![node(50, "con", "a", vec![])]),
+                node(6, "workspace", "2", vec![node(7, "con", "", vec![node(60, "con", "b", vec![])])]),
+            ]),
+        ]);
+        let tree: swayipc::Node = serde_json::from_value(tree).expect("valid tree");
+        assert_eq!(tree_workspace(&tree, 50).unwrap().as_deref(), Some("1"));
+        assert_eq!(tree_workspace(&tree, 60).unwrap().as_deref(), Some("2"));
+        assert_eq!(tree_workspace(&tree, 30).unwrap(), None);
+        assert!(tree_workspace(&tree, 99).is_err());
+    }
+
+    #[test]
+    fn workspace_name_escaped() {
+        assert_eq!(workspace_command("1: web"), r#"workspace --no-auto-back-and-forth "1: web""#);
+        assert_eq!(workspace_command(r#"a "b" \c\"#), r#"workspace --no-auto-back-and-forth "a \"b\" \\c\\""#);
+    }
+
+    #[test]
+    fn refocusing_keeps_forward() {
+        let mut history = history(true, &[1, 2, 3]);
+        assert_eq!(step(&mut history, false), Some(2));
+        // E.g. sway reporting the focus again on a click on the focused window.
+        history.focused(2);
+        assert_eq!(step(&mut history, true), Some(3));
+        assert_eq!(ids(&history), [1, 2, 3]);
+    }
+
+    /// A file path of its own for each test, in a directory cleaned up when dropped.
+    struct TempDir(PathBuf);
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("{}-{}-{}", env!("CARGO_PKG_NAME"), name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).expect("temp dir created");
+            Self(dir)
+        }
+    }
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn socket_path_joined() {
+        let socket = Path::new("/run/user/1000/sway-focus-switcheroo.socket");
+        assert_eq!(socket_path("/run/user/1000"), socket);
+        assert_eq!(socket_path("/run/user/1000/"), socket);
+        assert_eq!(socket_path("/run/user/1000/").to_str(), socket.to_str());
+        assert_eq!(socket_path("/tmp/my runtime dir/").to_str(), Some("/tmp/my runtime dir/sway-focus-switcheroo.socket"));
+    }
+
+    #[test]
+    fn live_socket_kept() {
+        let dir = TempDir::new("socket-live");
+        let path = socket_path(&dir.0);
+        let listener = UnixListener::bind(&path).unwrap();
+        let answer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut msg = String::new();
+            stream.read_to_string(&mut msg).unwrap();
+            assert_eq!(msg, "ping");
+            stream.write_all(b"pong").unwrap();
+        });
+        let e = check_stale(&path).unwrap_err();
+        assert!(e.to_string().contains("already running"), "{}", e);
+        answer.join().unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn stale_socket_replaced() {
+        let dir = TempDir::new("socket-stale");
+        let path = socket_path(&dir.0);
+        assert!(check_stale(&path).is_ok());
+        // Left behind by a listener that's gone.
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        assert!(check_stale(&path).is_ok());
+        // Or one that doesn't answer the ping.
+        let listener = UnixListener::bind(dir.0.join("mute.socket")).unwrap();
+        let mute = thread::spawn(move || drop(listener.accept().unwrap()));
+        assert!(check_stale(&dir.0.join("mute.socket")).is_ok());
+        mute.join().unwrap();
+    }
+
+    #[test]
+    fn unfinished_message_times_out() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        // Sent without shutting down the write half.
+        client.write_all(b"prev").unwrap();
+        let e = read_message(&mut server).unwrap_err();
+        assert!(matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut), "{:?}", e);
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"pr\xffev").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(read_message(&mut server).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        client.write_all(b"next").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(read_message(&mut server).unwrap(), "next");
+    }
+
+    #[test]
+    fn listen_args_checked() {
+        let parse = |args: &[&str]| ListenArgs::from_args(&["sway-focus-switcheroo", "listen"], args);
+        let args = parse(&["--back-forward", "--pid-file", "/run/user/1000/switcheroo.pid", "--notify"]).unwrap();
+        assert!(args.back_forward && args.notify && !args.raise_floating);
+        assert_eq!(args.pid_file.as_deref(), Some(Path::new("/run/user/1000/switcheroo.pid")));
+        assert!(parse(&[]).unwrap().pid_file.is_none());
+
+        assert!(parse(&["--raise-floatng"]).is_err());
+        assert!(parse(&["--pid-file", "--notify"]).is_err());
+        assert!(parse(&["--pid-file"]).is_err());
+        assert!(parse(&["daemon"]).is_err());
+    }
+
+    #[test]
+    fn second_listener_locked_out() {
+        let dir = TempDir::new("lock");
+        let path = dir.0.join("sway-focus-switcheroo.lock");
+        let lock = lock_listener(&path).expect("first lock taken");
+        // flock locks belong to the open file, so a second open is refused even in this process.
+        let e = lock_listener(&path).unwrap_err();
+        assert!(e.to_string().contains("already running"), "{}", e);
+        drop(lock);
+        assert!(lock_listener(&path).is_ok());
+    }
+
+    #[test]
+    fn pid_file_of_running_listener() {
+        assert!(is_listener(process::id()));
+        let dir = TempDir::new("pid-running");
+        let path = dir.0.join("pid");
+        fs::write(&path, format!("{}\n", process::id())).unwrap();
+        assert!(PidFile::create(path.clone()).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+    }
+
+    #[test]
+    fn pid_file_of_other_process() {
+        // The pid was reused by a process that isn't a listener.
+        let mut other = process::Command::new("sleep").arg("5").spawn().expect("sleep started");
+        assert!(!is_listener(other.id()));
+        let dir = TempDir::new("pid-reused");
+        let path = dir.0.join("pid");
+        fs::write(&path, format!("{}\n", other.id())).unwrap();
+        let pid_file = PidFile::create(path.clone()).expect("stale pid file replaced");
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+        let _ = other.kill();
+        let _ = other.wait();
+    }
+
+    #[test]
+    fn pid_file_of_exited_process() {
+        let mut exited = process::Command::new("true").spawn().expect("true started");
+        exited.wait().unwrap();
+        let dir = TempDir::new("pid-exited");
+        let path = dir.0.join("pid");
+        fs::write(&path, format!("{}\n", exited.id())).unwrap();
+        assert!(PidFile::create(path.clone()).is_ok());
+        fs::write(&path, "garbage").unwrap();
+        assert!(PidFile::create(path).is_ok());
     }
 }
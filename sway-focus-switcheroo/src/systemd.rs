@@ -0,0 +1,23 @@
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+pub use sd_notify::sd_notify;
+
+/// Pings the socket listener at half the watchdog interval, and tells systemd we're alive whenever
+/// it answers. Does nothing without WatchdogSec set.
+pub fn spawn_watchdog(socket_filename: PathBuf) {
+    let Some(usec) = env::var("WATCHDOG_USEC").ok().and_then(|s| s.parse::<u64>().ok()) else { return };
+    let interval = Duration::from_micros(usec) / 2;
+
+    thread::spawn(move || loop {
+        match crate::ping(&socket_filename) {
+            Ok(()) => if let Err(e) = sd_notify("WATCHDOG=1") {
+                eprintln!("Notifying the watchdog failed: {:?}", e);
+            },
+            Err(e) => eprintln!("Socket listener didn't answer ping: {:?}", e),
+        }
+        thread::sleep(interval);
+    });
+}
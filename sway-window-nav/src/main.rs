@@ -1,9 +1,22 @@
-use std::env;
 use std::cmp;
+use std::env;
 use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::process;
+use std::slice;
+use std::str;
+use std::{thread, time};
+use std::os::fd::AsFd;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
 use swayipc::Connection;
 use swayipc::{NodeLayout, NodeType};
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use argh::FromArgs;
+use serde_json::{json, Value};
 
 #[cfg(debug_assertions)]
 macro_rules! dbg_println {
@@ -25,7 +38,7 @@ macro_rules! dbg_dbg {
 // Sheesh!
 
 macro_rules! is_node_leaf {
-    ($n:expr) => { $n.nodes.is_empty() }
+    ($n:expr) => { $n.nodes.is_empty() && $n.floating_nodes.is_empty() }
 }
 
 
@@ -51,6 +64,12 @@ impl From<&swayipc::Rect> for Coord {
 }
 
 impl Coord {
+    fn euclidean_distance(&self, other: &Coord) -> f64 {
+        let dx = (self.x - other.x) as f64;
+        let dy = (self.y - other.y) as f64;
+        dx.hypot(dy)
+    }
+
     #[allow(dead_code)]
     fn asc_below_or_right_of(&self, b: &Coord) -> cmp::Ordering {
         if self == b {
@@ -73,13 +92,435 @@ impl Coord {
     }
 }
 
-enum CommandArgument {
+#[derive(Clone, Copy)]
+enum Direction {
     Next,
     Prev,
 }
+impl Direction {
+    fn reversed(self) -> Self {
+        match self {
+            Direction::Next => Direction::Prev,
+            Direction::Prev => Direction::Next,
+        }
+    }
+}
+impl str::FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(Direction::Next),
+            "prev" => Ok(Direction::Prev),
+            _ => Err("accepts only next or prev".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FocusTarget {
+    Direction(Direction),
+    CycleFloating,
+    CycleTiling,
+    Largest,
+    Smallest,
+}
+impl str::FromStr for FocusTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cycle-floating" => Ok(FocusTarget::CycleFloating),
+            "cycle-tiling" => Ok(FocusTarget::CycleTiling),
+            "largest" => Ok(FocusTarget::Largest),
+            "smallest" => Ok(FocusTarget::Smallest),
+            _ => s.parse().map(FocusTarget::Direction)
+                .map_err(|_| "accepts only next, prev, cycle-floating, cycle-tiling, largest or smallest".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MoveMode {
+    Swap,
+    Insert,
+}
+impl str::FromStr for MoveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "swap" => Ok(MoveMode::Swap),
+            "insert" => Ok(MoveMode::Insert),
+            _ => Err("accepts only swap or insert".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Boundary {
+    Workspace,
+    Output,
+    Global,
+}
+impl str::FromStr for Boundary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "workspace" => Ok(Boundary::Workspace),
+            "output" => Ok(Boundary::Output),
+            "global" => Ok(Boundary::Global),
+            _ => Err("accepts only workspace, output or global".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SingleWindowAction {
+    Nothing,
+    WorkspaceNext,
+}
+impl str::FromStr for SingleWindowAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nothing" => Ok(SingleWindowAction::Nothing),
+            "workspace-next" => Ok(SingleWindowAction::WorkspaceNext),
+            _ => Err("accepts only nothing or workspace-next".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FloatingOrder {
+    First,
+    Last,
+}
+impl str::FromStr for FloatingOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(FloatingOrder::First),
+            "last" => Ok(FloatingOrder::Last),
+            _ => Err("accepts only first or last".to_owned()),
+        }
+    }
+}
+
+fn count_from_str(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) | Err(_) => Err("count must be a positive integer".to_owned()),
+        Ok(n) => Ok(n),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Area(i32, i32);
+impl str::FromStr for Area {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once('x')
+            .and_then(|(w, h)| Some(Area(w.parse().ok()?, h.parse().ok()?)))
+            .ok_or_else(|| "expected WIDTHxHEIGHT in pixels".to_owned())
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
 enum Command {
-    Focus,
-    Move,
+    Focus(SubCommandFocus),
+    Move(SubCommandMove),
+    Swap(SubCommandSwap),
+    Insert(SubCommandInsert),
+    Status(SubCommandStatus),
+    Nearest(SubCommandNearest),
+    Continue(SubCommandContinue),
+    Column(SubCommandColumn),
+    Workspace(SubCommandWorkspace),
+    DebugTree(SubCommandDebugTree),
+    Place(SubCommandPlace),
+}
+impl Command {
+    /// Whether the command goes to another window in the ordering.
+    fn navigates(&self) -> bool {
+        matches!(self, Command::Focus(SubCommandFocus { mark: None, .. })
+            | Command::Move(_) | Command::Swap(_) | Command::Insert(_) | Command::Column(_) | Command::Place(_))
+    }
+
+    /// Flips the direction of next/prev navigation.
+    fn reverse(&mut self) {
+        match self {
+            Command::Focus(c) => if let Some(t) = c.target.first_mut() {
+                match t.as_str() {
+                    "next" => *t = "prev".to_owned(),
+                    "prev" => *t = "next".to_owned(),
+                    "largest" => *t = "smallest".to_owned(),
+                    "smallest" => *t = "largest".to_owned(),
+                    _ => {},
+                }
+            },
+            Command::Move(SubCommandMove { direction, .. })
+            | Command::Swap(SubCommandSwap { direction, .. })
+            | Command::Insert(SubCommandInsert { direction, .. })
+            | Command::Column(SubCommandColumn { direction, .. }) => *direction = direction.reversed(),
+            _ => {},
+        }
+    }
+}
+
+/// Focus the next or previous window, cycle through only the floating or tiling windows, or focus
+/// the largest or smallest window
+#[derive(FromArgs)]
+#[argh(subcommand, name = "focus")]
+struct SubCommandFocus {
+    /// next, prev, cycle-floating, cycle-tiling, largest or smallest, followed by how many windows
+    /// away to go (default: 1)
+    #[argh(positional, arg_name = "target")]
+    target: Vec<String>,
+    /// focus the window with the given mark instead
+    #[argh(option)]
+    mark: Option<String>,
+}
+impl SubCommandFocus {
+    // argh only allows the last positional argument to be optional, which doesn't fit
+    // "[<target> [<count>]]", so these are parsed here.
+    fn target(&self) -> Result<(FocusTarget, usize)> {
+        match self.target.as_slice() {
+            [target] => Ok((target.parse().map_err(|e| anyhow!("target {}", e))?, 1)),
+            [target, count] => Ok((
+                target.parse().map_err(|e| anyhow!("target {}", e))?,
+                count_from_str(count).map_err(|e| anyhow!(e))?,
+            )),
+            [] => bail!("focus requires either a target (next, prev, cycle-floating, cycle-tiling, largest or smallest) or --mark."),
+            _ => bail!("focus accepts only a target and a count."),
+        }
+    }
+}
+
+/// Move the focused window using the --default-move-mode
+#[derive(FromArgs)]
+#[argh(subcommand, name = "move")]
+struct SubCommandMove {
+    /// next or prev
+    #[argh(positional)]
+    direction: Direction,
+    /// how many windows away to go (default: 1)
+    #[argh(positional, default = "1", from_str_fn(count_from_str))]
+    count: usize,
+}
+
+/// Focus the window at the same row in the next or previous column of a horizontally split workspace
+#[derive(FromArgs)]
+#[argh(subcommand, name = "column")]
+struct SubCommandColumn {
+    /// next or prev
+    #[argh(positional)]
+    direction: Direction,
+    /// how many columns away to go (default: 1)
+    #[argh(positional, default = "1", from_str_fn(count_from_str))]
+    count: usize,
+}
+
+/// Print the workspace tree with the coordinates used for ordering, and the resulting order
+#[derive(FromArgs)]
+#[argh(subcommand, name = "debug-tree")]
+struct SubCommandDebugTree {
+    /// print the tree in graphviz dot format instead
+    #[argh(switch)]
+    graphviz: bool,
+}
+
+/// Move the focused window to the given position in the ordering
+#[derive(FromArgs)]
+#[argh(subcommand, name = "place")]
+struct SubCommandPlace {
+    /// position counting from 1, out of range positions go to the first or last place
+    #[argh(positional)]
+    position: usize,
+    /// swap with the window at the position, or insert there moving the rest along (default:
+    /// --default-move-mode)
+    #[argh(option)]
+    mode: Option<MoveMode>,
+}
+
+/// Workspace commands
+#[derive(FromArgs)]
+#[argh(subcommand, name = "workspace")]
+struct SubCommandWorkspace {
+    #[argh(subcommand)]
+    action: WorkspaceAction,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum WorkspaceAction {
+    Goto(SubCommandGoto),
+}
+
+/// Switch to the named workspace
+#[derive(FromArgs)]
+#[argh(subcommand, name = "goto")]
+struct SubCommandGoto {
+    /// workspace name
+    #[argh(positional)]
+    name: String,
+    /// focus the window last focused on the workspace, or the first one in order when the filters
+    /// leave it out
+    #[argh(switch)]
+    focus_mru: bool,
+}
+
+/// Swap the focused window with the next or previous window
+#[derive(FromArgs)]
+#[argh(subcommand, name = "swap")]
+struct SubCommandSwap {
+    /// next or prev
+    #[argh(positional)]
+    direction: Direction,
+    /// how many windows away to go (default: 1)
+    #[argh(positional, default = "1", from_str_fn(count_from_str))]
+    count: usize,
+}
+
+/// Insert the focused window after the next, or before the previous window
+#[derive(FromArgs)]
+#[argh(subcommand, name = "insert")]
+struct SubCommandInsert {
+    /// next or prev
+    #[argh(positional)]
+    direction: Direction,
+    /// how many windows away to go (default: 1)
+    #[argh(positional, default = "1", from_str_fn(count_from_str))]
+    count: usize,
+}
+
+/// Print the focused window's position in the ordering as JSON
+#[derive(FromArgs)]
+#[argh(subcommand, name = "status")]
+struct SubCommandStatus {
+    /// print just the index and the total, e.g. "3/7", for a window counter in a bar
+    #[argh(switch)]
+    print_index: bool,
+}
+
+/// Focus the window whose top left corner is nearest to the given point
+#[derive(FromArgs)]
+#[argh(subcommand, name = "nearest")]
+struct SubCommandNearest {
+    /// x coordinate in pixels
+    #[argh(option)]
+    x: i32,
+    /// y coordinate in pixels
+    #[argh(option)]
+    y: i32,
+}
+
+/// Repeat the previous focus or move, or focus next if there was none in the last minute
+#[derive(FromArgs)]
+#[argh(subcommand, name = "continue")]
+struct SubCommandContinue {
+    /// go the opposite way instead
+    #[argh(switch)]
+    reverse: bool,
+}
+
+#[derive(FromArgs)]
+/// Cycle focus, or move focused window, through all windows in the currently focused workspace.
+struct Args {
+    /// ignore sticky floating windows that belong to other workspaces on the focused output
+    #[argh(switch)]
+    no_sticky: bool,
+    /// whether floating windows come first or last among their siblings (default: last)
+    #[argh(option, default = "FloatingOrder::Last")]
+    floating_order: FloatingOrder,
+    /// navigate within the workspace with this name instead of the focused one
+    #[argh(option)]
+    workspace: Option<String>,
+    /// only cycle through the windows of the same application as the focused window
+    #[argh(switch)]
+    same_app: bool,
+    /// skip floating dialog and utility windows
+    #[argh(switch)]
+    skip_dialogs: bool,
+    /// with --skip-dialogs, also skip floating windows without an X11 window type that are
+    /// smaller than WIDTHxHEIGHT in area
+    #[argh(option)]
+    min_area: Option<Area>,
+    /// skip windows narrower than this, in logical pixels
+    #[argh(option)]
+    min_width: Option<i32>,
+    /// skip windows shorter than this, in logical pixels
+    #[argh(option)]
+    min_height: Option<i32>,
+    /// don't subtract the deco_rect y from the rect y of parent containers, for sway versions
+    /// that report parent rects without the decorations
+    #[argh(switch)]
+    no_legacy_deco: bool,
+    /// use the rects as sway reports them, without any of the workarounds, to check whether your
+    /// sway version still needs them (implies --no-legacy-deco)
+    #[argh(switch)]
+    raw_coords: bool,
+    /// stop at the first and last window instead of wrapping around
+    #[argh(switch)]
+    no_wrap: bool,
+    /// where focus next and prev wrap around: at the ends of the workspace, or going on through
+    /// the other workspaces of its output, or of all outputs, workspace, output or global
+    /// (default: workspace)
+    #[argh(option, default = "Boundary::Workspace")]
+    boundary: Boundary,
+    /// with focus next or prev inside a tabbed or stacked container, go through all of its
+    /// windows, wrapping around within it, before leaving it
+    #[argh(switch)]
+    hierarchical: bool,
+    /// what focus, move and the like do when the focused window is the only one, nothing or
+    /// workspace-next (default: nothing)
+    #[argh(option, default = "SingleWindowAction::Nothing")]
+    single_window_action: SingleWindowAction,
+    /// what move does, swap or insert (default: swap)
+    #[argh(option, default = "MoveMode::Swap")]
+    default_move_mode: MoveMode,
+    /// sway command(s) to swap windows with, {{focused}} and {{target}} are replaced with the
+    /// con_ids and {{focused_x}}, {{focused_y}}, {{target_x}}, {{target_y}} with the coordinates of
+    /// the windows (default: "swap container with con_id {{target}}")
+    #[argh(option, default = "DEFAULT_SWAP_COMMAND.to_owned()")]
+    swap_command: String,
+    /// move the cursor to the center of the newly focused window, for focus_follows_mouse
+    #[argh(switch)]
+    warp_cursor: bool,
+    /// seat whose cursor --warp-cursor moves (default: seat0)
+    #[argh(option, default = "\"seat0\".to_owned()")]
+    seat: String,
+    /// briefly set the opacity of the newly focused window to this, to show where the focus went
+    #[argh(option)]
+    flash: Option<f64>,
+    /// how long --flash lasts in milliseconds (default: 150)
+    #[argh(option, default = "150")]
+    flash_duration: u64,
+    /// run this shell command whenever next or prev wraps around from the last window to the first
+    /// or the other way around
+    #[argh(option)]
+    on_wrap_exec: Option<String>,
+    /// print the sway commands instead of running them
+    #[argh(switch)]
+    dry_run: bool,
+    /// save the tree as sway reported it to this file as JSON, to attach to bug reports about the
+    /// ordering
+    #[argh(option)]
+    capture: Option<PathBuf>,
+    /// include the windows left out by --min-width and --min-height in the status output, and
+    /// print the windows in order, with their app_id and title, to stderr
+    #[argh(switch)]
+    verbose: bool,
+    /// read commands from stdin, one per line, and run them over a single connection
+    #[argh(switch)]
+    stdin: bool,
+    /// command
+    #[argh(subcommand)]
+    command: Option<Command>,
 }
 
 // Used to squeeze out only the fields we care about from swayipc::Node, and to help testing.
@@ -87,46 +528,123 @@ enum Command {
 struct Node {
     id: i64,
     name: Option<String>,
+    app_id: Option<String>,
+    class: Option<String>,
+    /// X11 title, Wayland windows only have the name.
+    title: Option<String>,
+    window_type: Option<String>,
+    /// Whether this is a window, rather than an empty container.
+    window: bool,
     focused: bool,
+    floating: bool,
+    sticky: bool,
+    marks: Vec<String>,
     coords: Coord,
     deco_coords: Coord,
-    nodes: Vec<Node>,
+    width: i32,
+    height: i32,
+    tiling_nodes: Vec<Node>,
+    floating_nodes: Vec<Node>,
+}
+impl From<&swayipc::Node> for Node {
+    fn from(n: &swayipc::Node) -> Self {
+        Node::new(n, false, Quirks { parent_deco: true, tab_deco_y: true })
+    }
 }
-impl From<swayipc::Node> for Node {
-    fn from(n: swayipc::Node) -> Self {
+
+/// Workarounds for the rects sway reports, which look like bugs in sway.
+#[derive(Clone, Copy)]
+struct Quirks {
+    /// Parent nodes include the deco_rect y in their rect y.
+    parent_deco: bool,
+    /// A tabbed parent inside a tab has its deco_rect y set, unlike its siblings.
+    tab_deco_y: bool,
+}
+
+impl Quirks {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            parent_deco: !args.no_legacy_deco && !args.raw_coords,
+            tab_deco_y: !args.raw_coords,
+        }
+    }
+}
+
+/// The rect coordinates of `n`, with the parent deco offset taken out.
+fn normalized_coords(n: &swayipc::Node, quirks: Quirks) -> Coord {
+    Coord {
+        x: n.rect.x,
+        y: if is_node_leaf!(n) || !quirks.parent_deco {
+            n.rect.y
+        } else {
+            n.rect.y - n.deco_rect.y
+        }
+    }
+}
+
+/// The deco_rect coordinates of `n`, with the y left out as it can't be trusted.
+fn normalized_deco_coords(n: &swayipc::Node, quirks: Quirks) -> Coord {
+    Coord {
+        x: n.deco_rect.x,
+        y: if quirks.tab_deco_y { 0 } else { n.deco_rect.y },
+    }
+}
+
+impl Node {
+    /// `floating` marks the descendants of a floating container, which aren't floating_cons themselves.
+    fn new(n: &swayipc::Node, floating: bool, quirks: Quirks) -> Self {
+        // Empty containers, like a workspace without windows, are leaves too, but not windows.
+        let window = is_node_leaf!(n) && n.layout == NodeLayout::None;
         // Check if these assertions hold?
-        debug_assert!(!is_node_leaf!(n) || n.name.is_some());
-        debug_assert!(!is_node_leaf!(n) || n.visible.is_some());
-        debug_assert!(!is_node_leaf!(n) || n.layout == NodeLayout::None);
+        debug_assert!(!window || n.name.is_some());
+        debug_assert!(!window || n.visible.is_some());
         debug_assert!(n.layout != NodeLayout::Output);
         debug_assert!(n.layout != NodeLayout::Dockarea);
-        let c = Coord {
-            x: n.rect.x,
-            // Parent nodes include the deco_rect coordinates in the rect (bug in sway?).
-            y: if is_node_leaf!(n) {
-                n.rect.y
-            } else {
-                n.rect.y - n.deco_rect.y
-            }
+        let coords = normalized_coords(n, quirks);
+        let deco_coords = normalized_deco_coords(n, quirks);
+
+        let floating = floating || n.node_type == NodeType::FloatingCon;
+        let (class, title, window_type) = match &n.window_properties {
+            Some(p) => (p.class.clone(), p.title.clone(), p.window_type.clone()),
+            None => (None, None, None),
         };
 
         Self {
             id: n.id,
-            name: n.name,
+            name: n.name.clone(),
+            app_id: n.app_id.clone(),
+            class,
+            title,
+            window_type,
+            window,
             focused: n.focused,
-            coords: c,
-            deco_coords: Coord::from(&n.deco_rect),
-            nodes: n.nodes.into_iter().chain(n.floating_nodes.into_iter()).map(Node::from).collect(),
+            floating,
+            sticky: n.sticky,
+            marks: n.marks.clone(),
+            coords,
+            deco_coords,
+            width: n.rect.width,
+            height: n.rect.height,
+            tiling_nodes: n.nodes.iter().map(|c| Node::new(c, floating, quirks)).collect(),
+            floating_nodes: n.floating_nodes.iter().map(|c| Node::new(c, true, quirks)).collect(),
         }
     }
 }
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if is_node_leaf!(self) {
-            write!(f, "{}: {}, {}", self.id, self.coords, self.deco_coords)
+        if self.is_leaf() {
+            write!(f, "{}: {}, {}", self.id, self.coords, self.deco_coords)?;
+            if self.sticky {
+                write!(f, " (sticky)")?;
+            }
+            // {:#} adds what identifies the window, for --verbose.
+            if f.alternate() {
+                write!(f, " app_id {:?} title {:?}", self.app(), self.title.as_deref().or(self.name.as_deref()))?;
+            }
+            Ok(())
         } else {
             let mut pstr = String::new();
-            for p in &self.nodes {
+            for p in self.tiling_nodes.iter().chain(&self.floating_nodes) {
                 pstr.push_str(&format!("{}, ", p))
             }
             write!(f, "{}: {}, {} [{}]", self.id, self.coords, self.deco_coords, pstr)
@@ -134,100 +652,604 @@ impl fmt::Display for Node {
     }
 }
 impl Node {
+    fn is_leaf(&self) -> bool {
+        self.tiling_nodes.is_empty() && self.floating_nodes.is_empty()
+    }
+
+    /// Whether the window looks like a dialog, going by the X11 window type, or by the size when
+    /// there is no type to go by.
+    fn is_dialog(&self, min_area: Option<Area>) -> bool {
+        match (self.window_type.as_deref(), min_area) {
+            (Some(t), _) => t == "dialog" || t == "utility",
+            (None, Some(Area(w, h))) => i64::from(self.width) * i64::from(self.height) < i64::from(w) * i64::from(h),
+            (None, None) => false,
+        }
+    }
+
+    /// Ids of all the windows in this container.
+    fn leaf_ids(&self) -> Vec<i64> {
+        if self.is_leaf() {
+            vec![self.id]
+        } else {
+            self.tiling_nodes.iter().chain(&self.floating_nodes).flat_map(Node::leaf_ids).collect()
+        }
+    }
+
+    /// The center of the window, in layout coordinates.
+    fn center(&self) -> (i32, i32) {
+        (self.coords.x + self.width / 2, self.coords.y + self.height / 2)
+    }
+
+    /// Whether the window is narrower than `min_width` or shorter than `min_height`.
+    fn is_smaller_than(&self, min_width: Option<i32>, min_height: Option<i32>) -> bool {
+        min_width.is_some_and(|w| self.width < w) || min_height.is_some_and(|h| self.height < h)
+    }
+
+    /// The application the window belongs to, going by app_id or X11 window class.
+    fn app(&self) -> Option<&str> {
+        self.app_id.as_deref().or(self.class.as_deref())
+    }
+
+    /// Orders tabs and stacks by their titles, in reverse like the rest of the comparisons.
+    fn deco_order(&self, b: &Node) -> cmp::Ordering {
+        // Depth is the same, so the y is usually the same too, unless the tab_deco_y quirk is off.
+        (b.deco_coords.y, b.deco_coords.x).cmp(&(self.deco_coords.y, self.deco_coords.x))
+    }
+
     #[allow(dead_code)]
     fn asc_below_or_right_of(&self, b: &Node) -> cmp::Ordering {
-        self.coords.asc_below_or_right_of(&b.coords).reverse().then_with(|| {
-            // Depth is the same, we don't care about y.
-            // Tabbed parent inside a tab has deco_rect y set (incorrectly, bug in sway?).
-            let a = self.deco_coords.x;
-            let b = b.deco_coords.x;
-            b.cmp(&a)
-        })
+        self.coords.asc_below_or_right_of(&b.coords).reverse()
+            .then_with(|| self.deco_order(b))
+            .then_with(|| b.id.cmp(&self.id))
     }
 
     fn asc_above_or_right_of(&self, b: &Node) -> cmp::Ordering {
-        self.coords.asc_above_or_right_of(&b.coords).reverse().then_with(|| {
-            // Depth is the same, we don't care about y.
-            // Tabbed parent inside a tab has deco_rect y set (incorrectly, bug in sway?).
-            let a = self.deco_coords.x;
-            let b = b.deco_coords.x;
-            b.cmp(&a)
-        })
+        // Stacked windows can tie on both, so fall back to the id to keep the order deterministic;
+        // lower ids come first, as the sorted nodes are consumed from the back.
+        self.coords.asc_above_or_right_of(&b.coords).reverse()
+            .then_with(|| self.deco_order(b))
+            .then_with(|| b.id.cmp(&self.id))
     }
 }
 
-fn main()  -> Result<()> {
-    //
-    // Lazy arg parsing.
-    //
-    let args: Vec<String> = env::args().take(3).collect();
-
-    if args.len() < 3 {
-        let exe_path = env::current_exe()?;
-        let exe = exe_path.file_name().ok_or_else(|| anyhow!("Could not get executable file name."))?.to_string_lossy();
-        bail!("{} focus|move next|prev", exe);
+/// Takes out the sticky floating windows of all the other workspaces in `workspaces`. Sway keeps
+/// sticky windows in the tree of the workspace they were last on, even though they are visible on
+/// every workspace of the output.
+fn take_sticky_nodes(workspaces: &mut [swayipc::Node], focused_id: i64) -> Vec<swayipc::Node> {
+    let mut sticky = Vec::new();
+    for ws in workspaces.iter_mut().filter(|ws| ws.id != focused_id) {
+        let (s, rest): (Vec<_>, Vec<_>) = std::mem::take(&mut ws.floating_nodes).into_iter()
+            .partition(|n| n.sticky);
+        ws.floating_nodes = rest;
+        sticky.extend(s);
     }
+    sticky
+}
 
-    let cmd = match args[1].as_str() {
-        "focus" => Command::Focus,
-        "move" => Command::Move,
-        _ => bail!("First argument accepts only focus or move.")
-    };
-    let cmd_arg = match args[2].as_str() {
-        "next" => CommandArgument::Next,
-        "prev" => CommandArgument::Prev,
-        _ => bail!("Second argument accepts only next or prev.")
-    };
-
-    //
-    // Extract the currently focused workspace out of get_tree.
-    //
-    let mut conn = Connection::new()?;
-    let mut node = conn.get_tree()?;
-    assert!(node.node_type == NodeType::Root);
+/// The workspace called `name` in the tree, along with the sticky floating windows taken out of
+/// the other workspaces on its output when `with_sticky` is set.
+fn named_workspace<'a>(root: &'a mut swayipc::Node, name: &str, with_sticky: bool) -> Result<(&'a mut swayipc::Node, Vec<swayipc::Node>)> {
+    for output in &mut root.nodes {
+        if let Some(idx) = output.nodes.iter().position(|ws| ws.name.as_deref() == Some(name)) {
+            let sticky = if with_sticky {
+                let id = output.nodes[idx].id;
+                take_sticky_nodes(&mut output.nodes, id)
+            } else {
+                Vec::new()
+            };
+            return Ok((&mut output.nodes[idx], sticky));
+        }
+    }
+    bail!("Could not find a workspace called '{}'", name)
+}
 
+/// The focused workspace in the tree, following the focus stacks from the root, along with the
+/// sticky floating windows taken out of the other workspaces on its output when `with_sticky` is
+/// set.
+fn focused_workspace(root: &mut swayipc::Node, with_sticky: bool) -> Result<(&mut swayipc::Node, Vec<swayipc::Node>)> {
+    let mut node = root;
+    let mut sticky = Vec::new();
     while node.node_type != NodeType::Workspace {
-        let fid = node.focus.into_iter().next().ok_or_else(|| anyhow!("Could not find a focused output or workspace."))?;
+        let fid = node.focus.first().copied().ok_or_else(|| anyhow!("Could not find a focused output or workspace."))?;
+
+        if with_sticky && node.node_type == NodeType::Output {
+            sticky = take_sticky_nodes(&mut node.nodes, fid);
+        }
 
         // I suppose workspaces can't be in floating_nodes...
-        node = node.nodes.into_iter().find(|n| n.id == fid)
+        node = node.nodes.iter_mut().find(|n| n.id == fid)
             .ok_or_else(|| anyhow!("Could not find the focused workspace."))?;
 
         debug_assert!(matches!(node.node_type, NodeType::Output | NodeType::Workspace | NodeType::Dockarea));
     }
-    let workspace = node;
+    Ok((node, sticky))
+}
 
-    //
-    // Traverse the tree and collect all the leaves while sorting.
-    //
-    let mut node = Some(Node::from(workspace));
+/// The workspace with the id `id` in the tree.
+fn workspace_by_id(root: &swayipc::Node, id: i64) -> Option<&swayipc::Node> {
+    root.nodes.iter().flat_map(|o| &o.nodes).find(|ws| ws.id == id)
+}
+
+fn has_focus(n: &swayipc::Node) -> bool {
+    n.focused || n.nodes.iter().chain(&n.floating_nodes).any(has_focus)
+}
+
+/// Marks the window that would get the focus in `n` as focused, following the focus stacks, so
+/// that a workspace without the keyboard focus has somewhere to navigate from.
+fn mark_inactive_focus(mut n: &mut swayipc::Node) {
+    while let Some(&fid) = n.focus.first() {
+        match n.nodes.iter_mut().chain(n.floating_nodes.iter_mut()).find(|c| c.id == fid) {
+            Some(c) => n = c,
+            None => break,
+        }
+    }
+    n.focused = true;
+}
+
+/// Describes `n` as the comparator sees it, along with its place in the ordering of `windows`.
+fn debug_label(n: &swayipc::Node, windows: &[Node], quirks: Quirks) -> String {
+    let r = &n.rect;
+    let d = &n.deco_rect;
+    let mut label = format!(
+        "{} {:?} rect {} {}x{} deco_rect {} {}x{} coords {} deco_coords {}",
+        n.id, n.layout, Coord::from(r), r.width, r.height, Coord::from(d), d.width, d.height,
+        normalized_coords(n, quirks), normalized_deco_coords(n, quirks),
+    );
+    if let Some(i) = windows.iter().position(|w| w.id == n.id) {
+        // Counting from 1, like status.
+        label.push_str(&format!(" #{}", i + 1));
+    }
+    if let Some(name) = n.name.as_deref().filter(|_| is_node_leaf!(n)) {
+        label.push_str(&format!(" {:?}", name));
+    }
+    label
+}
+
+fn debug_tree(out: &mut String, n: &swayipc::Node, windows: &[Node], quirks: Quirks, depth: usize) {
+    out.push_str(&format!("{}{}\n", "  ".repeat(depth), debug_label(n, windows, quirks)));
+    for c in n.nodes.iter().chain(&n.floating_nodes) {
+        debug_tree(out, c, windows, quirks, depth + 1);
+    }
+}
+
+fn debug_tree_dot(out: &mut String, n: &swayipc::Node, windows: &[Node], quirks: Quirks) {
+    out.push_str(&format!("  n{} [shape=box, label={:?}];\n", n.id, debug_label(n, windows, quirks)));
+    for c in n.nodes.iter().chain(&n.floating_nodes) {
+        out.push_str(&format!("  n{} -> n{};\n", n.id, c.id));
+        debug_tree_dot(out, c, windows, quirks);
+    }
+}
+
+/// The innermost tabbed or stacked container holding the focused window in `n`, with the ids of
+/// all the windows in it.
+fn focused_tab_group(mut n: &swayipc::Node) -> Option<(i64, Vec<i64>)> {
+    if !has_focus(n) {
+        return None;
+    }
+    let mut group = None;
+    loop {
+        if matches!(n.layout, NodeLayout::Tabbed | NodeLayout::Stacked) {
+            group = Some(n);
+        }
+        match n.nodes.iter().chain(&n.floating_nodes).find(|c| has_focus(c)) {
+            Some(c) => n = c,
+            None => break,
+        }
+    }
+    group.map(|g| (g.id, window_ids(g)))
+}
+
+fn window_ids(n: &swayipc::Node) -> Vec<i64> {
+    if is_node_leaf!(n) {
+        vec![n.id]
+    } else {
+        n.nodes.iter().chain(&n.floating_nodes).flat_map(window_ids).collect()
+    }
+}
+
+/// Id of the window that would get the focus in `n`, following the focus stacks.
+fn inactive_focus_id(mut n: &swayipc::Node) -> i64 {
+    while let Some(c) = n.focus.first().and_then(|&fid| n.nodes.iter().chain(&n.floating_nodes).find(|c| c.id == fid)) {
+        n = c;
+    }
+    n.id
+}
+
+fn workspace_command(name: &str) -> String {
+    format!("workspace \"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Escapes `s` for use in a sway criteria regex.
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$\"".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn status(windows: &[Node], wrap: bool, filtered: Option<&[i64]>) -> Value {
+    let focused_idx = windows.iter().position(|x| x.focused);
+    let focused = focused_idx.map(|i| &windows[i]);
+    let prev = focused_idx.and_then(|i| step(i, windows.len(), Direction::Prev, 1, wrap));
+    let next = focused_idx.and_then(|i| step(i, windows.len(), Direction::Next, 1, wrap));
+    let mut status = json!({
+        "focused_id": focused.map(|w| w.id),
+        "app_id": focused.and_then(|w| w.app_id.as_deref()),
+        // Counting from 1, for display.
+        "index": focused_idx.map(|i| i + 1),
+        "total": windows.len(),
+        "prev": prev.map(|i| windows[i].id),
+        "next": next.map(|i| windows[i].id),
+        "prev_wraps": matches!((focused_idx, prev), (Some(f), Some(p)) if p > f),
+        "next_wraps": matches!((focused_idx, next), (Some(f), Some(n)) if n < f),
+    });
+    if let Some(filtered) = filtered {
+        status["filtered"] = json!(filtered);
+    }
+    status
+}
+
+/// Id of the window `count` windows `direction` of the focused one, or None if that is the focused
+/// window itself, or if no window is focused.
+fn next_window(windows: &[Node], direction: Direction, count: usize, wrap: bool) -> Option<i64> {
+    let focused_idx = windows.iter().position(|x| x.focused)?;
+    dbg_dbg!(focused_idx);
+    step(focused_idx, windows.len(), direction, count, wrap).map(|i| windows[i].id)
+}
+
+/// The workspaces of each output, the outputs from left to right, leaving out the scratchpad.
+fn output_workspaces(root: &swayipc::Node) -> Vec<Vec<&swayipc::Node>> {
+    let mut outputs: Vec<&swayipc::Node> = root.nodes.iter()
+        .filter(|o| o.node_type == NodeType::Output && o.name.as_deref() != Some("__i3"))
+        .collect();
+    outputs.sort_by_key(|o| (o.rect.x, o.rect.y));
+    outputs.into_iter()
+        .map(|o| o.nodes.iter().filter(|w| w.node_type == NodeType::Workspace).collect())
+        .collect()
+}
+
+/// With next or prev going `count` windows past the ends of the `windows` of the workspace
+/// `current`, the first or last window of the next or previous one of `workspaces` that has any,
+/// along with whether that wrapped around the ends of `workspaces`.
+fn next_workspace_window(workspaces: &[&swayipc::Node], current: i64, windows: &[Node], direction: Direction, count: usize, args: &Args, quirks: Quirks) -> Option<(Node, bool)> {
+    let focused_idx = windows.iter().position(|w| w.focused)?;
+    let past_end = match direction {
+        Direction::Next => focused_idx + count >= windows.len(),
+        Direction::Prev => count > focused_idx,
+    };
+    let idx = workspaces.iter().position(|w| w.id == current).filter(|_| past_end)?;
+    let len = workspaces.len();
+    let app = windows[focused_idx].app();
+
+    for i in 1..len {
+        let (next, wrapped) = match direction {
+            Direction::Next => ((idx + i) % len, idx + i >= len),
+            Direction::Prev => ((idx + len - i) % len, i > idx),
+        };
+        if wrapped && args.no_wrap {
+            break;
+        }
+        let (mut others, _) = collect_windows(Node::new(workspaces[next], false, quirks), args, false);
+        others.retain(|w| is_visited(w, args, Some(app)));
+        let window = match direction {
+            Direction::Next => others.into_iter().next(),
+            Direction::Prev => others.pop(),
+        };
+        if let Some(window) = window {
+            return Some((window, wrapped));
+        }
+    }
+    None
+}
+
+/// The window `count` steps away from the focused one with --hierarchical: inside the tab group
+/// made up of the windows in `group`, the next one in the group, wrapping around within it, until
+/// coming back to `entry`, the window the group was entered at. From there on it's the usual order,
+/// past the end of the group. Also tells whether it wrapped around the ends of the whole ordering.
+fn next_in_group(windows: &[Node], group: &[i64], entry: i64, direction: Direction, count: usize, wrap: bool) -> Option<(i64, bool)> {
+    let members: Vec<usize> = windows.iter().enumerate()
+        .filter(|(_, w)| group.contains(&w.id))
+        .map(|(i, _)| i)
+        .collect();
+    let focused_idx = windows.iter().position(|w| w.focused)?;
+    let mut idx = focused_idx;
+    let mut wrapped = false;
+
+    for _ in 0..count {
+        let from = match members.iter().position(|&m| m == idx) {
+            Some(pos) if members.len() > 1 => {
+                let next = members[step(pos, members.len(), direction, 1, true)?];
+                if windows[next].id != entry {
+                    idx = next;
+                    continue;
+                }
+                // Went through the whole group, leave it from its far end.
+                match direction {
+                    Direction::Next => members[members.len() - 1],
+                    Direction::Prev => members[0],
+                }
+            },
+            _ => idx,
+        };
+        let Some(next) = step(from, windows.len(), direction, 1, wrap) else { break };
+        wrapped |= match direction {
+            Direction::Next => next < from,
+            Direction::Prev => next > from,
+        };
+        idx = next;
+    }
+
+    (idx != focused_idx).then(|| (windows[idx].id, wrapped))
+}
+
+/// Whether going `direction` from the focused window to `target` wraps around the end of the
+/// ordering.
+fn wraps(windows: &[Node], direction: Direction, target: i64) -> bool {
+    let focused_idx = windows.iter().position(|w| w.focused);
+    let target_idx = windows.iter().position(|w| w.id == target);
+    match (focused_idx, target_idx, direction) {
+        (Some(f), Some(t), Direction::Next) => t < f,
+        (Some(f), Some(t), Direction::Prev) => t > f,
+        _ => false,
+    }
+}
+
+/// Index of the window `count` windows `direction` of `idx`, either wrapping around or stopping at
+/// the ends. None if that ends up back at `idx`.
+fn step(idx: usize, len: usize, direction: Direction, count: usize, wrap: bool) -> Option<usize> {
+    let next = match (direction, wrap) {
+        (Direction::Next, true) => (idx + count % len) % len,
+        (Direction::Prev, true) => (idx + len - count % len) % len,
+        (Direction::Next, false) => idx.saturating_add(count).min(len - 1),
+        (Direction::Prev, false) => idx.saturating_sub(count),
+    };
+    (next != idx).then_some(next)
+}
+
+/// Whether --skip-dialogs and --same-app keep `w`, with `app` the app of the focused window, if
+/// there is one. The focused window itself is always kept.
+fn is_visited(w: &Node, args: &Args, app: Option<Option<&str>>) -> bool {
+    if w.focused {
+        return true;
+    }
+    if args.skip_dialogs && w.floating && w.is_dialog(args.min_area) {
+        return false;
+    }
+    match app {
+        // Windows that set neither app_id nor class don't match anything.
+        Some(app) if args.same_app => matches!((w.app(), app), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b)),
+        _ => true,
+    }
+}
+
+/// Focuses the window `target`, and with `warp_seat` also moves that seat's cursor to its center.
+fn focus_commands(windows: &[Node], target: i64, warp_seat: Option<&str>) -> Vec<String> {
+    let mut cmds = vec![format!("[con_id={}] focus", target)];
+    if let Some(seat) = warp_seat {
+        if let Some(w) = windows.iter().find(|w| w.id == target) {
+            let (x, y) = w.center();
+            cmds.push(format!("seat {} cursor set {} {}", seat, x, y));
+        }
+    }
+    cmds
+}
+
+/// The signals that would otherwise kill the process in the middle of a flash.
+fn interrupt_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    for signal in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+        signals.add(signal);
+    }
+    signals
+}
+
+/// Runs `set` on `conn`, and `restore` once `duration` has passed, or as soon as SIGINT, SIGTERM or
+/// SIGHUP is pending, in which case it returns true. The signals have to be blocked, as `main` does,
+/// so that they stay pending until `restore` has run.
+fn flash<C>(conn: &mut C, duration: time::Duration, set: impl FnOnce(&mut C) -> Result<()>, restore: impl FnOnce(&mut C)) -> Result<bool> {
+    let signals = SignalFd::with_flags(&interrupt_signals(), SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+    set(conn)?;
+    // Readable once a signal is pending, which is left for main to let through.
+    let mut fds = [PollFd::new(signals.as_fd(), PollFlags::POLLIN)];
+    let interrupted = poll(&mut fds, PollTimeout::try_from(duration).unwrap_or(PollTimeout::MAX));
+    restore(conn);
+    Ok(interrupted? > 0)
+}
+
+/// Briefly sets the opacity of the focused window to `opacity`, to show where the focus went. Sway
+/// doesn't report the opacity of windows, so this assumes it was the default when setting it back.
+fn flash_focused(conn: &mut Connection, opacity: f64, duration: time::Duration) -> Result<()> {
+    let Some(id) = conn.get_tree()?.find_as_ref(|n| n.focused).map(|n| n.id) else { return Ok(()) };
+    flash(
+        conn, duration,
+        |conn| run_commands(conn, &[format!("[con_id={}] opacity {}", id, opacity)]),
+        |conn| if let Err(e) = run_commands(conn, &[format!("[con_id={}] opacity 1", id)]) {
+            eprintln!("Failed to restore the opacity of {}: {:#}", id, e);
+        },
+    )?;
+    Ok(())
+}
+
+/// Bumped whenever the saved state changes in an incompatible way.
+const STATE_VERSION: u64 = 1;
+/// How long `continue` considers the saved state to be relevant.
+const STATE_MAX_AGE: time::Duration = time::Duration::from_secs(60);
+
+fn state_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|d| PathBuf::from(d).join("sway-window-nav.state"))
+}
+
+fn unix_time() -> Result<u64> {
+    Ok(time::SystemTime::now().duration_since(time::UNIX_EPOCH)?.as_secs())
+}
+
+/// Saves the arguments of a focus or move, for `continue` to repeat, along with the id of the tab
+/// group --hierarchical is going through and the window it entered the group at.
+fn save_state(args: &[String], group: Option<(i64, i64)>) -> Result<()> {
+    let Some(path) = state_path() else { return Ok(()) };
+    let mut state = json!({ "version": STATE_VERSION, "time": unix_time()?, "args": args });
+    if let Some(group) = group {
+        state["group"] = json!(group);
+    }
+    fs::write(&path, state.to_string()).with_context(|| format!("Could not write {}", path.display()))
+}
+
+/// The saved state, unless it is stale or from an incompatible version.
+fn read_state() -> Option<Value> {
+    let state: Value = serde_json::from_str(&fs::read_to_string(state_path()?).ok()?).ok()?;
+    if state["version"].as_u64()? != STATE_VERSION {
+        return None;
+    }
+    let age = unix_time().ok()?.checked_sub(state["time"].as_u64()?)?;
+    if age > STATE_MAX_AGE.as_secs() {
+        return None;
+    }
+    Some(state)
+}
+
+/// The saved arguments of the previous focus or move.
+fn load_state() -> Option<Vec<String>> {
+    serde_json::from_value(read_state()?["args"].take()).ok()
+}
+
+/// The tab group --hierarchical was going through and the window it was entered at.
+fn load_group() -> Option<(i64, i64)> {
+    serde_json::from_value(read_state()?["group"].take()).ok()
+}
+
+const INSERT_MARK: &str = "_sway-window-nav-insert";
+
+const DEFAULT_SWAP_COMMAND: &str = "swap container with con_id {target}";
+
+/// Fills in the con_ids and coordinates of the focused and target windows in a --swap-command
+/// template, splitting it into its commands.
+fn swap_commands(template: &str, focused: &Node, target: &Node) -> Vec<String> {
+    let cmd = template
+        .replace("{focused}", &focused.id.to_string())
+        .replace("{focused_x}", &focused.coords.x.to_string())
+        .replace("{focused_y}", &focused.coords.y.to_string())
+        .replace("{target}", &target.id.to_string())
+        .replace("{target_x}", &target.coords.x.to_string())
+        .replace("{target_y}", &target.coords.y.to_string());
+    cmd.split(';').map(str::trim).filter(|c| !c.is_empty()).map(str::to_owned).collect()
+}
+
+fn move_commands(mode: MoveMode, direction: Direction, windows: &[Node], target: i64, swap_command: &str) -> Vec<String> {
+    match mode {
+        MoveMode::Swap => {
+            // next_window() already made sure both are there.
+            let focused = windows.iter().find(|w| w.focused).expect("focused window");
+            let target = windows.iter().find(|w| w.id == target).expect("target window");
+            swap_commands(swap_command, focused, target)
+        },
+        MoveMode::Insert => {
+            let mut cmds = vec![
+                format!("[con_id={}] mark --add {}", target, INSERT_MARK),
+                format!("move container to mark {}", INSERT_MARK),
+            ];
+            // Moving to a mark places the container after the marked one.
+            if let Direction::Prev = direction {
+                cmds.push(format!("swap container with con_id {}", target));
+            }
+            cmds.push(format!("[con_id={}] unmark {}", target, INSERT_MARK));
+            cmds
+        }
+    }
+}
+
+/// Runs `cmds` as a single sway IPC message, reporting the first command that failed.
+fn run_commands(conn: &mut Connection, cmds: &[String]) -> Result<()> {
+    let payload = cmds.join("; ");
+    dbg_dbg!(&payload);
+    for (cmd, res) in cmds.iter().zip(conn.run_command(&payload)?) {
+        res.with_context(|| format!("Sway command '{}' failed", cmd))?;
+    }
+    Ok(())
+}
+
+/// Starts `hook` through the shell without waiting for it, only reporting a failure to start it.
+fn spawn_hook(hook: &str) {
+    let spawned = process::Command::new("sh").arg("-c").arg(hook)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .spawn();
+    match spawned {
+        // Reaped in the background, for when --stdin keeps running.
+        Ok(mut child) => drop(thread::spawn(move || child.wait())),
+        Err(e) => eprintln!("Failed to run '{}': {}", hook, e),
+    }
+}
+
+/// Runs `cmds`, or with `dry_run` returns them for printing instead.
+fn execute(conn: &mut Connection, cmds: &[String], dry_run: bool) -> Result<Option<Value>> {
+    if dry_run {
+        return Ok(Some(Value::String(cmds.join("; "))));
+    }
+    run_commands(conn, cmds)?;
+    Ok(None)
+}
+
+/// Collects the windows of `workspace` in the order they are navigated in, along with the ids of
+/// the ones left out for being smaller than --min-width or --min-height. With `stacking_order` the
+/// floating windows are left in their stacking order instead of being sorted.
+fn collect_windows(workspace: Node, args: &Args, stacking_order: bool) -> (Vec<Node>, Vec<i64>) {
+    let mut node = Some(workspace);
     let mut stack = Vec::new();
     let mut _depth = 0;
     let mut windows = Vec::new();
+    // Ids of the windows left out for being too small.
+    let mut filtered = Vec::new();
 
     while node.is_some() || !stack.is_empty() {
         if let Some(mut n) = node.take() {
-            if is_node_leaf!(n) {
+            if n.is_leaf() {
                 dbg_println!("{} visiting leaf {}: {:?}", "-".repeat(_depth+1), n.id, n.name);
-                windows.push(n);
+                // The focused window is always kept, so that there is somewhere to start from.
+                if !n.window {
+                    continue;
+                } else if !n.focused && n.is_smaller_than(args.min_width, args.min_height) {
+                    filtered.push(n.id);
+                } else {
+                    windows.push(n);
+                }
             } else {
                 dbg_println!("{} found branch {}", "-".repeat(_depth), n.id);
                 _depth += 1;
-                dbg_println!("{} children {:?}", "-".repeat(_depth), n.nodes.iter().map(|x| x.id).collect::<Vec<_>>());
+                dbg_println!("{} children {:?} floating {:?}", "-".repeat(_depth),
+                             n.tiling_nodes.iter().map(|x| x.id).collect::<Vec<_>>(),
+                             n.floating_nodes.iter().map(|x| x.id).collect::<Vec<_>>());
 
                 // Sorting here ensures the windows are sorted by rect/deco_rect, while still respecting the
                 // structure of the tree. Also this means we don't usually have to do much, since the windows
                 // are often already in order.
-                n.nodes.sort_unstable_by(Node::asc_above_or_right_of);
-                dbg_println!("{} sorted children {:?}", "-".repeat(_depth), n.nodes.iter().map(|x| x.id).collect::<Vec<_>>());
+                // Floating windows are sorted separately, as comparing their coordinates against the tiling
+                // ones makes little sense.
+                // Cycling through only the floating windows goes by their stacking order instead, which
+                // sway keeps from bottom to top, so it's reversed to come out bottommost first.
+                n.tiling_nodes.sort_unstable_by(Node::asc_above_or_right_of);
+                if stacking_order {
+                    n.floating_nodes.reverse();
+                } else {
+                    n.floating_nodes.sort_unstable_by(Node::asc_above_or_right_of);
+                }
+
+                // The branch is consumed from the back.
+                let children = match args.floating_order {
+                    FloatingOrder::First => n.tiling_nodes.into_iter().chain(n.floating_nodes).collect::<Vec<_>>(),
+                    FloatingOrder::Last => n.floating_nodes.into_iter().chain(n.tiling_nodes).collect::<Vec<_>>(),
+                };
+                dbg_println!("{} sorted children {:?}", "-".repeat(_depth), children.iter().map(|x| x.id).collect::<Vec<_>>());
 
-                stack.push(n.nodes);
+                stack.push(children);
             }
         } else if let Some(mut v) = stack.pop() {
             if v.is_empty() {
                 _depth -= 1;
                 dbg_println!("{} consumed branch", "-".repeat(_depth));
-                assert!(!windows.is_empty());
             } else {
                 // Move to the next node of the current branch in the stack.
                 node = v.pop();
@@ -241,44 +1263,1093 @@ fn main()  -> Result<()> {
     debug_assert!(node.is_none(), "{:?}", node);
     debug_assert!(stack.is_empty(), "{:?}", stack);
 
-    // For tests:
-    dbg_println!("<id>: <rect>, <deco_rect>");
-    #[cfg(debug_assertions)]
-    for w in &windows {
-        dbg_println!("{}", w);
+    if args.verbose {
+        eprintln!("<id>: <rect>, <deco_rect>");
+        for w in &windows {
+            eprintln!("{:#}", w);
+        }
+    } else {
+        dbg_println!("<id>: <rect>, <deco_rect>");
+        #[cfg(debug_assertions)]
+        for w in &windows {
+            dbg_println!("{}", w);
+        }
+    }
+
+    (windows, filtered)
+}
+
+fn main()  -> Result<()> {
+    // Held back while a command runs, so that an interrupt can't leave a --flash window dimmed.
+    // Blocked before any thread is spawned, like the --on-wrap-exec reapers, so that they all
+    // inherit it and the signals go to this thread once it lets them through.
+    let signals = interrupt_signals();
+    signals.thread_block()?;
+
+    let args: Args = argh::from_env();
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let mut conn = Connection::new()?;
+
+    if args.stdin {
+        // The options given along with --stdin apply to every command read.
+        let globals: Vec<String> = argv.into_iter().filter(|a| a != "--stdin").collect();
+        return run_stdin(&mut conn, &globals, &signals);
+    }
+
+    match run(&mut conn, args, argv)? {
+        Some(Value::String(s)) => println!("{}", s),
+        Some(output) => println!("{}", output),
+        None => {},
+    }
+    // Now go down the way a pending signal would have taken us.
+    signals.thread_unblock()?;
+    Ok(())
+}
+
+/// Runs the commands read from stdin one line at a time, writing a line of JSON about each.
+/// `signals` are let through only while waiting for the next line.
+fn run_stdin(conn: &mut Connection, globals: &[String], signals: &SigSet) -> Result<()> {
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        signals.thread_unblock()?;
+        let line = lines.next();
+        signals.thread_block()?;
+        let Some(line) = line else { break };
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let argv: Vec<String> = globals.iter().cloned().chain(line.split_whitespace().map(str::to_owned)).collect();
+        let strs: Vec<&str> = argv.iter().map(String::as_str).collect();
+        let res = match Args::from_args(&[env!("CARGO_PKG_NAME")], &strs) {
+            Ok(args) if args.stdin => Err(anyhow!("--stdin can't be given on stdin")),
+            Ok(args) => run(conn, args, argv),
+            Err(e) => Err(anyhow!("{}", e.output.trim())),
+        };
+        let result = match res {
+            Ok(Some(output)) => json!({ "ok": true, "output": output }),
+            Ok(None) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+        };
+        let mut stdout = io::stdout().lock();
+        writeln!(stdout, "{}", result)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Runs a single command, returning what it has to say: the status, or the commands of a dry run.
+/// `argv` are the arguments `args` were parsed from, for `continue` to repeat.
+fn run(conn: &mut Connection, mut args: Args, argv: Vec<String>) -> Result<Option<Value>> {
+    let Some(mut command) = args.command.take() else { bail!("No command given, see --help.") };
+
+    // The arguments `continue` repeats.
+    let mut state_args = matches!(command, Command::Focus(_) | Command::Move(_) | Command::Swap(_) | Command::Insert(_) | Command::Column(_))
+        .then_some(argv);
+    if let Command::Continue(SubCommandContinue { reverse }) = command {
+        let prev = load_state().and_then(|saved| {
+            let strs: Vec<&str> = saved.iter().map(String::as_str).collect();
+            let mut prev = Args::from_args(&[env!("CARGO_PKG_NAME")], &strs).ok().filter(|p| !p.stdin)?;
+            let prev_command = prev.command.take()?;
+            Some((saved, prev, prev_command))
+        });
+        match prev {
+            Some((saved, prev, prev_command)) => {
+                args = prev;
+                command = prev_command;
+                state_args = Some(saved);
+            },
+            None => command = Command::Focus(SubCommandFocus { target: vec!["next".to_owned()], mark: None }),
+        }
+        if reverse {
+            command.reverse();
+        }
+    }
+
+    if let Command::Focus(c @ SubCommandFocus { mark: None, .. }) = &command {
+        c.target()?;
+    }
+
+    let tree = conn.get_tree()?;
+    if let Some(path) = &args.capture {
+        let tree = serde_json::to_string_pretty(&tree)?;
+        fs::write(path, tree).with_context(|| format!("Could not write {}", path.display()))?;
+    }
+
+    let navigates = command.navigates();
+    let flash = args.flash.filter(|_| matches!(command, Command::Focus(_) | Command::Nearest(_) | Command::Column(_) | Command::Workspace(_)));
+    let saved_group = if args.hierarchical { load_group() } else { None };
+    let (cmds, wrapped, group_state) = match plan(tree, &args, command, saved_group)? {
+        Plan::Nothing => return Ok(None),
+        Plan::Output(output) => return Ok(Some(output)),
+        Plan::Run(cmds) => return execute(conn, &cmds, args.dry_run),
+        Plan::Go { cmds, wrapped, group } => (cmds, wrapped, group),
+    };
+
+    if args.dry_run {
+        let payload = Value::String(cmds.join("; "));
+        return Ok(Some(if navigates { json!({ "commands": payload, "wrapped": wrapped }) } else { payload }));
+    }
+    run_commands(conn, &cmds)?;
+
+    if let (true, Some(hook)) = (wrapped, &args.on_wrap_exec) {
+        spawn_hook(hook);
+    }
+
+    // The focus already moved, so only `continue` is lost if saving fails.
+    if let Some(state_args) = state_args {
+        if let Err(e) = save_state(&state_args, group_state) {
+            eprintln!("Failed to save the state for continue: {:#}", e);
+        }
+    }
+
+    if let Some(opacity) = flash {
+        flash_focused(conn, opacity, time::Duration::from_millis(args.flash_duration))?;
     }
+    Ok(None)
+}
+
+/// What a command comes down to, given the tree.
+#[derive(Debug, PartialEq)]
+enum Plan {
+    /// Nothing to do, e.g. as there is no window to go to.
+    Nothing,
+    /// Something to print instead, like the status.
+    Output(Value),
+    /// Sway commands to run as they are.
+    Run(Vec<String>),
+    /// Sway commands that go to another window. `wrapped` tells whether next or prev went around
+    /// the end of the ordering, and `group` is the tab group --hierarchical is going through, with
+    /// the window it was entered at.
+    Go { cmds: Vec<String>, wrapped: bool, group: Option<(i64, i64)> },
+}
+
+/// Works out what `command` does on `tree`, the whole tree from get_tree. `saved_group` is the tab
+/// group the previous --hierarchical focus was going through, with the window it was entered at.
+fn plan(tree: swayipc::Node, args: &Args, command: Command, saved_group: Option<(i64, i64)>) -> Result<Plan> {
+    let wrap = !args.no_wrap;
 
     //
-    // Construct and run a sway IPC command.
+    // Find the currently focused workspace in get_tree.
     //
-    let focused_idx = windows.iter().position(|x| x.focused)
-        .ok_or_else(|| anyhow!("Could not find the focused window"))?;
-    dbg_dbg!(focused_idx);
+    let mut tree = tree;
+    assert!(tree.node_type == NodeType::Root);
 
-    let next_idx = match cmd_arg {
-        CommandArgument::Next => {
-            if windows.len() > focused_idx + 1 {
-                focused_idx + 1
-            } else {
-                0
+    let goto = match &command {
+        Command::Workspace(SubCommandWorkspace { action: WorkspaceAction::Goto(g) }) => Some(g.name.clone()),
+        _ => None,
+    };
+    // The most recently used window of the workspace to go to, which unlike the focused window isn't
+    // exempt from the filters.
+    let mut mru = None;
+
+    let (workspace, sticky) = match goto.as_ref().or(args.workspace.as_ref()) {
+        Some(name) => {
+            let (workspace, sticky) = match named_workspace(&mut tree, name, !args.no_sticky) {
+                Ok(found) => found,
+                // Sway creates the workspace, which has no windows to pick from.
+                Err(_) if goto.is_some() => return Ok(Plan::Run(vec![workspace_command(name)])),
+                Err(e) => return Err(e),
+            };
+            if !has_focus(workspace) {
+                if goto.is_some() {
+                    mru = Some(inactive_focus_id(workspace));
+                } else {
+                    mark_inactive_focus(workspace);
+                }
             }
+            (workspace, sticky)
         },
-        CommandArgument::Prev => {
-            if focused_idx > 0 {
-                focused_idx - 1
+        None => focused_workspace(&mut tree, !args.no_sticky)?,
+    };
+
+    // Don't visit a sticky window twice, should sway ever list it in more than one place.
+    let ids: Vec<i64> = workspace.floating_nodes.iter().map(|n| n.id).collect();
+    workspace.floating_nodes.extend(sticky.into_iter().filter(|n| !ids.contains(&n.id)));
+    let workspace_id = workspace.id;
+    let workspace = workspace_by_id(&tree, workspace_id).expect("workspace found above");
+
+    // The workspaces focus next and prev go through with --boundary.
+    let focus_direction = matches!(&command, Command::Focus(c @ SubCommandFocus { mark: None, .. })
+                                   if matches!(c.target(), Ok((FocusTarget::Direction(_), _))));
+    let boundary_workspaces: Vec<&swayipc::Node> = match args.boundary {
+        Boundary::Output if focus_direction => output_workspaces(&tree).into_iter().find(|o| o.iter().any(|w| w.id == workspace_id)).unwrap_or_default(),
+        Boundary::Global if focus_direction => output_workspaces(&tree).into_iter().flatten().collect(),
+        _ => Vec::new(),
+    };
+
+    //
+    // Traverse the tree and collect all the leaves while sorting.
+    //
+    let stacking_order = matches!(&command, Command::Focus(c @ SubCommandFocus { mark: None, .. })
+                                  if matches!(c.target(), Ok((FocusTarget::CycleFloating, _))));
+    let workspace_layout = workspace.layout;
+    let quirks = Quirks::from_args(args);
+    // Kept for printing, as Node leaves out what doesn't matter for the ordering.
+    let raw_workspace = matches!(command, Command::DebugTree(_)).then_some(workspace);
+    let tab_group = focused_tab_group(workspace).filter(|_| args.hierarchical);
+    let workspace = Node::new(workspace, false, quirks);
+
+    // The top level containers of a horizontally split workspace make up its columns, left to
+    // right.
+    let columns: Vec<Vec<i64>> = if workspace_layout == NodeLayout::SplitH {
+        let mut columns: Vec<_> = workspace.tiling_nodes.iter().collect();
+        columns.sort_by_key(|c| c.coords.x);
+        columns.into_iter().map(Node::leaf_ids).collect()
+    } else {
+        Vec::new()
+    };
+
+    let (mut windows, filtered) = collect_windows(workspace, args, stacking_order);
+
+    // Whether the focused window is the only one on the workspace, before leaving any out.
+    let only_window = filtered.is_empty() && matches!(windows.as_slice(), [w] if w.focused);
+
+    //
+    // Filter out the windows we don't want to visit.
+    //
+    let app = windows.iter().find(|w| w.focused).map(|w| w.app().map(str::to_owned));
+    windows.retain(|w| is_visited(w, args, app.as_ref().map(Option::as_deref)));
+
+    // With nothing to navigate to, don't go through the motions of focusing or swapping with itself.
+    // Unless there are other workspaces to go on to.
+    let only_workspace = boundary_workspaces.len() <= 1;
+    if command.navigates() && only_window && only_workspace {
+        return match args.single_window_action {
+            SingleWindowAction::Nothing => Ok(Plan::Nothing),
+            SingleWindowAction::WorkspaceNext => Ok(Plan::Run(vec!["workspace next".to_owned()])),
+        };
+    }
+    if command.navigates() && only_workspace && matches!(windows.as_slice(), [w] if w.focused) {
+        return Ok(Plan::Nothing);
+    }
+
+    //
+    // Construct and run a sway IPC command.
+    //
+    let warp_seat = args.warp_cursor.then_some(args.seat.as_str());
+    // Whether next or prev went around the end of the ordering, for --on-wrap-exec.
+    let mut wrapped = false;
+    // The tab group --hierarchical is going through, and the window it was entered at.
+    let mut group_state = None;
+    let cmds = match command {
+        Command::Continue(_) => unreachable!("continue was replaced with the command to repeat"),
+        Command::DebugTree(SubCommandDebugTree { graphviz }) => {
+            let raw_workspace = raw_workspace.expect("workspace kept for debug-tree");
+            // Returned rather than printed, so that --stdin keeps to a line per command.
+            let mut out = String::new();
+            if graphviz {
+                out.push_str("digraph workspace {\n");
+                debug_tree_dot(&mut out, raw_workspace, &windows, quirks);
+                out.push('}');
             } else {
-                windows.len() - 1
+                debug_tree(&mut out, raw_workspace, &windows, quirks, 0);
+                out.pop();
             }
-        }
-    };
+            return Ok(Plan::Output(Value::String(out)));
+        },
+        Command::Status(SubCommandStatus { print_index: true }) => {
+            // Nothing to count from on a workspace without a focused window.
+            let index = windows.iter().position(|w| w.focused)
+                .map_or_else(String::new, |i| format!("{}/{}", i + 1, windows.len()));
+            return Ok(Plan::Output(Value::String(index)));
+        },
+        Command::Status(_) => {
+            return Ok(Plan::Output(status(&windows, wrap, args.verbose.then_some(filtered.as_slice()))));
+        },
+        Command::Nearest(SubCommandNearest { x, y }) => {
+            let point = Coord { x, y };
+            let nearest = windows.iter().min_by(|a, b| {
+                a.coords.euclidean_distance(&point).total_cmp(&b.coords.euclidean_distance(&point))
+                    .then(a.id.cmp(&b.id))
+            });
+            match nearest {
+                Some(w) => focus_commands(&windows, w.id, warp_seat),
+                None => return Ok(Plan::Nothing),
+            }
+        },
+        Command::Focus(SubCommandFocus { mark: Some(mark), .. }) => {
+            // Marks are unique, but the window may well be on some other workspace, in which case let
+            // sway look it up.
+            match windows.iter().find(|w| w.marks.contains(&mark)) {
+                Some(w) => focus_commands(&windows, w.id, warp_seat),
+                None => vec![format!("[con_mark=\"^{}$\"] focus", escape_regex(&mark))],
+            }
+        },
+        Command::Focus(c) => match c.target()? {
+            (FocusTarget::Direction(direction), count) => {
+                // With the focus somewhere without windows, e.g. on an empty workspace or output, start
+                // from the first or the last window instead.
+                // Past the ends of the workspace, --boundary goes on to the next workspace with windows.
+                let other = tab_group.is_none()
+                    .then(|| next_workspace_window(&boundary_workspaces, workspace_id, &windows, direction, count, args, quirks))
+                    .flatten();
+                if let Some((window, wrapped)) = other {
+                    return Ok(Plan::Go { cmds: focus_commands(slice::from_ref(&window), window.id, warp_seat), wrapped, group: None });
+                }
+                let target = if let (Some((group_id, group)), Some(focused)) = (&tab_group, windows.iter().find(|w| w.focused)) {
+                    // Entered at the focused window, unless going through the same group already.
+                    let entry = saved_group
+                        .filter(|(id, entry)| id == group_id && group.contains(entry))
+                        .map_or(focused.id, |(_, entry)| entry);
+                    let Some((target, group_wrapped)) = next_in_group(&windows, group, entry, direction, count, wrap) else { return Ok(Plan::Nothing) };
+                    if group.contains(&target) {
+                        group_state = Some((*group_id, entry));
+                    }
+                    wrapped = group_wrapped;
+                    Some(target)
+                } else if windows.iter().any(|w| w.focused) {
+                    next_window(&windows, direction, count, wrap)
+                } else {
+                    match direction {
+                        Direction::Next => windows.first(),
+                        Direction::Prev => windows.last(),
+                    }.map(|w| w.id)
+                };
+                let Some(target) = target else { return Ok(Plan::Nothing) };
+                if tab_group.is_none() {
+                    wrapped = wraps(&windows, direction, target);
+                }
+                focus_commands(&windows, target, warp_seat)
+            },
+            (target @ (FocusTarget::Largest | FocusTarget::Smallest), _) => {
+                // The lowest id wins a tie, so that the same window is picked every time.
+                let area = |w: &Node| i64::from(w.width) * i64::from(w.height);
+                let window = match target {
+                    FocusTarget::Largest => windows.iter().max_by_key(|w| (area(w), cmp::Reverse(w.id))),
+                    _ => windows.iter().min_by_key(|w| (area(w), w.id)),
+                };
+                let Some(window) = window.filter(|w| !w.focused) else { return Ok(Plan::Nothing) };
+                focus_commands(&windows, window.id, warp_seat)
+            },
+            (target, count) => {
+                // Cycle within the floating or tiling subset only, entering it from its first window when
+                // the focus is currently outside of it.
+                let floating = matches!(target, FocusTarget::CycleFloating);
+                windows.retain(|w| w.floating == floating);
+                if windows.is_empty() {
+                    return Ok(Plan::Nothing);
+                }
+
+                let target = if floating {
+                    // Focusing raises the window to the top, so going to the bottommost one each time goes
+                    // through all of them, whichever one of them the focus started at.
+                    let below: Vec<&Node> = windows.iter().filter(|w| !w.focused).collect();
+                    if below.is_empty() {
+                        return Ok(Plan::Nothing);
+                    }
+                    below[(count - 1) % below.len()].id
+                } else if windows.iter().any(|w| w.focused) {
+                    let Some(target) = next_window(&windows, Direction::Next, count, wrap) else { return Ok(Plan::Nothing) };
+                    wrapped = wraps(&windows, Direction::Next, target);
+                    target
+                } else {
+                    windows[0].id
+                };
+                focus_commands(&windows, target, warp_seat)
+            },
+        },
+        Command::Workspace(SubCommandWorkspace { action: WorkspaceAction::Goto(SubCommandGoto { name, focus_mru }) }) => {
+            let mut cmds = vec![workspace_command(&name)];
+            if focus_mru {
+                let mru = mru.or_else(|| windows.iter().find(|w| w.focused).map(|w| w.id));
+                let target = mru.filter(|id| windows.iter().any(|w| w.id == *id))
+                    .or_else(|| windows.first().map(|w| w.id));
+                if let Some(target) = target {
+                    cmds.extend(focus_commands(&windows, target, warp_seat));
+                }
+            }
+            cmds
+        },
+        Command::Column(SubCommandColumn { direction, count }) => {
+            // The rows of each column are its windows in the usual order.
+            let columns: Vec<Vec<&Node>> = columns.iter()
+                .map(|ids| windows.iter().filter(|w| ids.contains(&w.id)).collect::<Vec<_>>())
+                .filter(|c| !c.is_empty())
+                .collect();
+            let focused = columns.iter().enumerate()
+                .find_map(|(ci, c)| c.iter().position(|w| w.focused).map(|ri| (ci, ri)));
 
-    let cmd_msg = match cmd {
-        Command::Focus => format!("[con_id={}] focus", windows[next_idx].id),
-        Command::Move => format!("swap container with con_id {}", windows[next_idx].id)
+            let target = match focused {
+                Some((ci, ri)) => {
+                    let Some(next_ci) = step(ci, columns.len(), direction, count, wrap) else { return Ok(Plan::Nothing) };
+                    wrapped = match direction {
+                        Direction::Next => next_ci < ci,
+                        Direction::Prev => next_ci > ci,
+                    };
+                    columns[next_ci][ri.min(columns[next_ci].len() - 1)].id
+                },
+                // Not in a column, e.g. floating or not a horizontal split, so go by the usual order.
+                None => {
+                    let Some(target) = next_window(&windows, direction, count, wrap) else { return Ok(Plan::Nothing) };
+                    wrapped = wraps(&windows, direction, target);
+                    target
+                },
+            };
+            focus_commands(&windows, target, warp_seat)
+        },
+        Command::Place(SubCommandPlace { position, mode }) => {
+            let Some(focused_idx) = windows.iter().position(|w| w.focused) else { return Ok(Plan::Nothing) };
+            let target_idx = position.saturating_sub(1).min(windows.len() - 1);
+            // Inserting before a window is done the same way as moving to the previous one.
+            let direction = match target_idx.cmp(&focused_idx) {
+                cmp::Ordering::Equal => return Ok(Plan::Nothing),
+                cmp::Ordering::Less => Direction::Prev,
+                cmp::Ordering::Greater => Direction::Next,
+            };
+            let mode = mode.unwrap_or(args.default_move_mode);
+            move_commands(mode, direction, &windows, windows[target_idx].id, &args.swap_command)
+        },
+        Command::Move(SubCommandMove { direction, count }) => {
+            let Some(target) = next_window(&windows, direction, count, wrap) else { return Ok(Plan::Nothing) };
+            wrapped = wraps(&windows, direction, target);
+            move_commands(args.default_move_mode, direction, &windows, target, &args.swap_command)
+        },
+        Command::Swap(SubCommandSwap { direction, count }) => {
+            let Some(target) = next_window(&windows, direction, count, wrap) else { return Ok(Plan::Nothing) };
+            wrapped = wraps(&windows, direction, target);
+            move_commands(MoveMode::Swap, direction, &windows, target, &args.swap_command)
+        },
+        Command::Insert(SubCommandInsert { direction, count }) => {
+            let Some(target) = next_window(&windows, direction, count, wrap) else { return Ok(Plan::Nothing) };
+            wrapped = wraps(&windows, direction, target);
+            move_commands(MoveMode::Insert, direction, &windows, target, &args.swap_command)
+        },
     };
 
-    dbg_dbg!(&cmd_msg);
-    conn.run_command(cmd_msg)?;
+    Ok(Plan::Go { cmds, wrapped, group: group_state })
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sys::pthread::{pthread_kill, pthread_self};
+
+    type Rect = (i32, i32, i32, i32);
+
+    fn rect((x, y, width, height): Rect) -> Value {
+        json!({ "x": x, "y": y, "width": width, "height": height })
+    }
+
+    /// A node of the sway tree as get_tree reports it, with the fields that don't matter for the
+    /// navigation filled in.
+    fn node(id: i64, node_type: &str, layout: &str, r: Rect, deco: Rect, nodes: Vec<Value>) -> Value {
+        json!({
+            "id": id, "name": null, "type": node_type, "border": "normal", "current_border_width": 2,
+            "layout": layout, "percent": null, "rect": rect(r), "window_rect": rect(r),
+            "deco_rect": rect(deco), "geometry": rect(r), "urgent": false, "focused": false,
+            "focus": nodes.iter().map(|n| n["id"].clone()).collect::<Vec<_>>(),
+            "nodes": nodes, "floating_nodes": [], "sticky": false,
+        })
+    }
+
+    fn window(id: i64, r: Rect, deco: Rect) -> Value {
+        let mut w = node(id, "con", "none", r, deco, vec![]);
+        w["name"] = json!(format!("window {}", id));
+        w["visible"] = json!(true);
+        w
+    }
+
+    fn con(id: i64, layout: &str, r: Rect, deco: Rect, nodes: Vec<Value>) -> Value {
+        node(id, "con", layout, r, deco, nodes)
+    }
+
+    fn workspace(id: i64, layout: &str, nodes: Vec<Value>) -> Value {
+        let mut ws = node(id, "workspace", layout, (0, 0, 1920, 1080), (0, 0, 0, 0), nodes);
+        ws["name"] = json!(id.to_string());
+        ws
+    }
+
+    fn focused(mut n: Value) -> Value {
+        n["focused"] = json!(true);
+        n
+    }
+
+    /// The whole tree, of a single output with the given workspaces, the first one focused.
+    fn tree(workspaces: Vec<Value>) -> Value {
+        let output = node(100, "output", "output", (0, 0, 1920, 1080), (0, 0, 0, 0), workspaces);
+        node(101, "root", "splith", (0, 0, 1920, 1080), (0, 0, 0, 0), vec![output])
+    }
+
+    /// What the command line `argv` does on `tree`.
+    fn plan_for(tree: &Value, argv: &[&str]) -> Plan {
+        plan_in_group(tree, argv, None)
+    }
+
+    /// What the command line `argv` does on `tree`, with `saved_group` left by the previous
+    /// --hierarchical focus.
+    fn plan_in_group(tree: &Value, argv: &[&str], saved_group: Option<(i64, i64)>) -> Plan {
+        let mut args = args(argv);
+        let command = args.command.take().expect("command");
+        plan(serde_json::from_value(tree.clone()).expect("valid tree"), &args, command, saved_group).unwrap()
+    }
+
+    /// Sets the node `id` somewhere in `n` as the only focused one.
+    fn focus_on(n: &mut Value, id: i64) {
+        n["focused"] = json!(n["id"] == id);
+        for key in ["nodes", "floating_nodes"] {
+            for c in n[key].as_array_mut().into_iter().flatten() {
+                focus_on(c, id);
+            }
+        }
+    }
+
+    /// The commands that focus `id`.
+    fn go_to(id: i64, wrapped: bool) -> Plan {
+        Plan::Go { cmds: vec![format!("[con_id={}] focus", id)], wrapped, group: None }
+    }
+
+    fn args(argv: &[&str]) -> Args {
+        Args::from_args(&["sway-window-nav"], argv).expect("valid arguments")
+    }
+
+    /// Ids of the windows of `workspace` in the order they are navigated in, with the options of
+    /// `argv`.
+    fn order(workspace: &Value, argv: &[&str]) -> Vec<i64> {
+        let args = args(argv);
+        let workspace: swayipc::Node = serde_json::from_value(workspace.clone()).expect("valid node");
+        let workspace = Node::new(&workspace, false, Quirks::from_args(&args));
+        collect_windows(workspace, &args, false).0.iter().map(|w| w.id).collect()
+    }
+
+    /// A tabbed container of window 10 and the tabbed container 11 of windows 12 and 13, with the
+    /// tabs in the given order. The inner container has its deco_rect y set, and with `legacy`
+    /// its rect y includes it, as older sway versions report them.
+    fn nested_tabs(inner_first: bool, legacy: bool) -> Value {
+        let (inner_x, window_x) = if inner_first { (0, 960) } else { (960, 0) };
+        let inner_y = if legacy { 50 } else { 25 };
+        workspace(1, "splith", vec![
+            con(2, "tabbed", (0, 0, 1920, 1080), (0, 0, 0, 0), vec![
+                window(10, (0, 25, 1920, 1055), (window_x, 0, 960, 25)),
+                con(11, "tabbed", (0, inner_y, 1920, 1055), (inner_x, 25, 960, 25), vec![
+                    window(12, (0, 50, 1920, 1030), (0, 0, 960, 25)),
+                    window(13, (0, 50, 1920, 1030), (960, 0, 960, 25)),
+                ]),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn legacy_deco_on_legacy_tree() {
+        // The inner tabs come first, which only the workaround sees on these rects.
+        let ws = nested_tabs(true, true);
+        assert_eq!(order(&ws, &[]), [12, 13, 10]);
+        assert_eq!(order(&ws, &["--no-legacy-deco"]), [10, 12, 13]);
+    }
+
+    #[test]
+    fn legacy_deco_on_fixed_tree() {
+        // The window tab comes first, which the workaround gets wrong once the rect y is right.
+        let ws = nested_tabs(false, false);
+        assert_eq!(order(&ws, &["--no-legacy-deco"]), [10, 12, 13]);
+        assert_eq!(order(&ws, &[]), [12, 13, 10]);
+    }
+
+    #[test]
+    fn tab_deco_y_quirk() {
+        // Only the inner container has its deco_rect y set, which would put it after the window tab
+        // if it were compared.
+        let ws = nested_tabs(true, false);
+        assert_eq!(order(&ws, &["--no-legacy-deco"]), [12, 13, 10]);
+        assert_eq!(order(&ws, &["--raw-coords"]), [10, 12, 13]);
+    }
+
+    #[test]
+    fn normalized_coords_per_quirk() {
+        let inner: swayipc::Node = serde_json::from_value(nested_tabs(true, true)["nodes"][0]["nodes"][1].clone()).unwrap();
+        let all = Quirks::from_args(&args(&[]));
+        let no_legacy = Quirks::from_args(&args(&["--no-legacy-deco"]));
+        let raw = Quirks::from_args(&args(&["--raw-coords"]));
+        assert_eq!(normalized_coords(&inner, all), Coord { x: 0, y: 25 });
+        assert_eq!(normalized_coords(&inner, no_legacy), Coord { x: 0, y: 50 });
+        assert_eq!(normalized_coords(&inner, raw), Coord { x: 0, y: 50 });
+        assert_eq!(normalized_deco_coords(&inner, all), Coord { x: 0, y: 0 });
+        assert_eq!(normalized_deco_coords(&inner, no_legacy), Coord { x: 0, y: 0 });
+        assert_eq!(normalized_deco_coords(&inner, raw), Coord { x: 0, y: 25 });
+
+        // Windows are left alone either way.
+        let window: swayipc::Node = serde_json::from_value(nested_tabs(true, true)["nodes"][0]["nodes"][0].clone()).unwrap();
+        assert_eq!(normalized_coords(&window, all), Coord { x: 0, y: 25 });
+    }
+
+    #[test]
+    fn ties_broken_by_id() {
+        // A stack of windows with the same rect, told apart only by the deco_rect y, which the
+        // tab_deco_y quirk leaves out.
+        let stack = |ids: [i64; 3]| workspace(1, "splith", vec![
+            con(2, "stacked", (0, 0, 1920, 1080), (0, 0, 0, 0), ids.iter().enumerate()
+                .map(|(i, &id)| window(id, (0, 75, 1920, 1005), (0, 25 * i as i32, 1920, 25)))
+                .collect()),
+        ]);
+        for ids in [[20, 21, 22], [22, 21, 20], [21, 22, 20], [20, 22, 21]] {
+            assert_eq!(order(&stack(ids), &[]), [20, 21, 22], "{:?}", ids);
+        }
+    }
+
+    /// A workspace of three windows in a row, none of them focused, with the focus stack of the
+    /// workspace pointing at an empty container instead.
+    fn unfocused_workspace() -> Value {
+        let mut ws = workspace(1, "splith", vec![
+            window(10, (0, 0, 640, 1080), (0, 0, 0, 0)),
+            window(11, (640, 0, 640, 1080), (0, 0, 0, 0)),
+            con(12, "splitv", (1280, 0, 0, 1080), (0, 0, 0, 0), vec![]),
+            window(13, (1280, 0, 640, 1080), (0, 0, 0, 0)),
+        ]);
+        ws["focus"] = json!([12, 13, 11, 10]);
+        ws
+    }
+
+    #[test]
+    fn no_focused_window() {
+        let tree = tree(vec![unfocused_workspace()]);
+        assert_eq!(plan_for(&tree, &["focus", "next"]), go_to(10, false));
+        assert_eq!(plan_for(&tree, &["focus", "prev"]), go_to(13, false));
+        assert_eq!(plan_for(&tree, &["move", "next"]), Plan::Nothing);
+        assert_eq!(plan_for(&tree, &["status", "--print-index"]), Plan::Output(json!("")));
+    }
+
+    #[test]
+    fn no_focused_window_on_named_workspace() {
+        // The focus stack is followed down to the empty container, which isn't a window.
+        let tree = tree(vec![workspace(2, "splith", vec![]), unfocused_workspace()]);
+        assert_eq!(plan_for(&tree, &["--workspace", "1", "focus", "next"]), go_to(10, false));
+        assert_eq!(plan_for(&tree, &["--workspace", "1", "focus", "prev"]), go_to(13, false));
+    }
+
+    #[test]
+    fn empty_workspace() {
+        let tree = tree(vec![focused(workspace(1, "splith", vec![]))]);
+        assert_eq!(plan_for(&tree, &["focus", "next"]), Plan::Nothing);
+        assert_eq!(plan_for(&tree, &["move", "next"]), Plan::Nothing);
+    }
+
+    #[test]
+    fn parse_focus_target() {
+        assert!(matches!("next".parse(), Ok(FocusTarget::Direction(Direction::Next))));
+        assert!(matches!("prev".parse(), Ok(FocusTarget::Direction(Direction::Prev))));
+        assert!(matches!("cycle-floating".parse(), Ok(FocusTarget::CycleFloating)));
+        assert!(matches!("cycle-tiling".parse(), Ok(FocusTarget::CycleTiling)));
+        assert!(matches!("largest".parse(), Ok(FocusTarget::Largest)));
+        assert!(matches!("smallest".parse(), Ok(FocusTarget::Smallest)));
+        let Err(e) = "biggest".parse::<FocusTarget>() else { panic!("biggest parsed") };
+        assert!(e.contains("largest or smallest"), "{}", e);
+    }
+
+    /// Windows of different sizes, with 23 and 24 of the same area.
+    fn varied_sizes() -> Value {
+        tree(vec![workspace(1, "splith", vec![
+            window(20, (0, 0, 400, 1080), (0, 0, 0, 0)),
+            con(21, "splitv", (400, 0, 1000, 1080), (0, 0, 0, 0), vec![
+                window(22, (400, 0, 1000, 700), (0, 0, 0, 0)),
+                window(23, (400, 700, 1000, 380), (0, 0, 0, 0)),
+            ]),
+            window(24, (1400, 0, 500, 760), (0, 0, 0, 0)),
+        ])])
+    }
+
+    #[test]
+    fn focus_largest_and_smallest() {
+        let mut tree = varied_sizes();
+        tree["nodes"][0]["nodes"][0]["nodes"][1]["nodes"][0]["focused"] = json!(true);
+        assert_eq!(plan_for(&tree, &["focus", "largest"]), Plan::Nothing);
+        // 1000x380 and 500x760 tie for smallest, and the lower id wins.
+        assert_eq!(plan_for(&tree, &["focus", "smallest"]), go_to(23, false));
+
+        tree["nodes"][0]["nodes"][0]["nodes"][1]["nodes"][0]["focused"] = json!(false);
+        tree["nodes"][0]["nodes"][0]["nodes"][0]["focused"] = json!(true);
+        assert_eq!(plan_for(&tree, &["focus", "largest"]), go_to(22, false));
+        assert_eq!(plan_for(&tree, &["focus", "smallest"]), go_to(23, false));
+    }
+
+    /// Window 20, tabbed container 21 holding windows 22, 23 and 24, and window 25, side by side.
+    fn tab_group() -> Value {
+        let tab = |id, i| window(id, (640, 25, 640, 1055), (i * 213, 0, 213, 25));
+        tree(vec![workspace(1, "splith", vec![
+            window(20, (0, 0, 640, 1080), (0, 0, 0, 0)),
+            con(21, "tabbed", (640, 0, 640, 1080), (0, 0, 0, 0), vec![tab(22, 0), tab(23, 1), tab(24, 2)]),
+            window(25, (1280, 0, 640, 1080), (0, 0, 0, 0)),
+        ])])
+    }
+
+    fn go_in_group(id: i64, group: (i64, i64)) -> Plan {
+        Plan::Go { cmds: vec![format!("[con_id={}] focus", id)], wrapped: false, group: Some(group) }
+    }
+
+    #[test]
+    fn hierarchical_goes_around_the_group() {
+        let mut tree = tab_group();
+        let next = ["--hierarchical", "focus", "next"];
+        focus_on(&mut tree, 23);
+        assert_eq!(plan_for(&tree, &next), go_in_group(24, (21, 23)));
+        focus_on(&mut tree, 24);
+        assert_eq!(plan_in_group(&tree, &next, Some((21, 23))), go_in_group(22, (21, 23)));
+        // Back at the window the group was entered at, leave it past its far end.
+        focus_on(&mut tree, 22);
+        assert_eq!(plan_in_group(&tree, &next, Some((21, 23))), go_to(25, false));
+        // Without the saved group, 22 is where it was entered at.
+        assert_eq!(plan_for(&tree, &next), go_in_group(23, (21, 22)));
+
+        let prev = ["--hierarchical", "focus", "prev"];
+        focus_on(&mut tree, 23);
+        assert_eq!(plan_for(&tree, &prev), go_in_group(22, (21, 23)));
+        focus_on(&mut tree, 24);
+        assert_eq!(plan_in_group(&tree, &prev, Some((21, 23))), go_to(20, false));
+        // Counts step through the group too.
+        focus_on(&mut tree, 23);
+        assert_eq!(plan_for(&tree, &["--hierarchical", "focus", "next", "2"]), go_in_group(22, (21, 23)));
+        // Without --hierarchical it's the plain order.
+        assert_eq!(plan_for(&tree, &["focus", "next"]), go_to(24, false));
+        focus_on(&mut tree, 24);
+        assert_eq!(plan_for(&tree, &["focus", "next"]), go_to(25, false));
+    }
+
+    #[test]
+    fn hierarchical_stale_group_is_ignored() {
+        let mut tree = tab_group();
+        focus_on(&mut tree, 22);
+        // Some other group, or an entry no longer in this one, start over from the focused window.
+        assert_eq!(plan_in_group(&tree, &["--hierarchical", "focus", "next"], Some((99, 23))), go_in_group(23, (21, 22)));
+        assert_eq!(plan_in_group(&tree, &["--hierarchical", "focus", "next"], Some((21, 99))), go_in_group(23, (21, 22)));
+        // Outside of any group it's the plain order.
+        focus_on(&mut tree, 20);
+        assert_eq!(plan_in_group(&tree, &["--hierarchical", "focus", "next"], Some((21, 23))), go_to(22, false));
+    }
+
+    #[test]
+    fn hierarchical_nested_tabs() {
+        let mut tree = tree(vec![nested_tabs(false, false)]);
+        assert_eq!(order(&tree["nodes"][0]["nodes"][0], &[]), vec![12, 13, 10]);
+        // The innermost group is the one gone through.
+        focus_on(&mut tree, 12);
+        let next = ["--hierarchical", "focus", "next"];
+        assert_eq!(plan_for(&tree, &next), go_in_group(13, (11, 12)));
+        focus_on(&mut tree, 13);
+        assert_eq!(plan_in_group(&tree, &next, Some((11, 12))), go_to(10, false));
+        // The outer group when focused on a window directly inside it.
+        focus_on(&mut tree, 10);
+        assert_eq!(plan_for(&tree, &next), go_in_group(12, (2, 10)));
+    }
+
+    #[test]
+    fn status_of_focused_window() {
+        let mut tree = tab_group();
+        focus_on(&mut tree, 20);
+        let Plan::Output(status) = plan_for(&tree, &["status"]) else { panic!("no status") };
+        assert_eq!(status, json!({
+            "focused_id": 20, "app_id": null, "index": 1, "total": 5, "prev": 25, "next": 22,
+            "prev_wraps": true, "next_wraps": false,
+        }));
+    }
+
+    #[test]
+    fn debug_tree_is_returned() {
+        let mut tree = tab_group();
+        focus_on(&mut tree, 20);
+        let Plan::Output(Value::String(text)) = plan_for(&tree, &["debug-tree"]) else { panic!("no tree") };
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 7, "{}", text);
+        assert!(lines[0].starts_with("1 SplitH rect"), "{}", lines[0]);
+        assert!(lines[4].starts_with("    23 None") && lines[4].contains(" #3 "), "{}", lines[4]);
+
+        let Plan::Output(Value::String(dot)) = plan_for(&tree, &["debug-tree", "--graphviz"]) else { panic!("no graph") };
+        assert!(dot.starts_with("digraph workspace {\n  n1 [shape=box"), "{}", dot);
+        assert!(dot.contains("\n  n21 -> n23;\n") && dot.ends_with(";\n}"), "{}", dot);
+    }
+
+    #[test]
+    fn single_window_action() {
+        let mut tree = tree(vec![workspace(1, "splith", vec![window(20, (0, 0, 1920, 1080), (0, 0, 0, 0))])]);
+        focus_on(&mut tree, 20);
+        let workspace_next = Plan::Run(vec!["workspace next".to_owned()]);
+        for cmd in [["focus", "next"], ["move", "next"]] {
+            assert_eq!(plan_for(&tree, &cmd), Plan::Nothing);
+            let argv = ["--single-window-action", "workspace-next", cmd[0], cmd[1]];
+            assert_eq!(plan_for(&tree, &argv), workspace_next);
+        }
+    }
+
+    #[test]
+    fn single_window_action_counts_filtered_windows() {
+        let mut tree = tree(vec![workspace(1, "splith", vec![
+            window(20, (0, 0, 1800, 1080), (0, 0, 0, 0)),
+            window(21, (1800, 0, 120, 1080), (0, 0, 0, 0)),
+        ])]);
+        tree["nodes"][0]["nodes"][0]["nodes"][0]["app_id"] = json!("foot");
+        tree["nodes"][0]["nodes"][0]["nodes"][1]["app_id"] = json!("firefox");
+        focus_on(&mut tree, 20);
+        // Other windows are there, just left out, so the workspace isn't switched.
+        for filter in [&["--same-app"][..], &["--min-width", "200"]] {
+            for cmd in [["focus", "next"], ["move", "next"]] {
+                let argv: Vec<&str> = ["--single-window-action", "workspace-next"].iter()
+                    .chain(filter).chain(&cmd).copied().collect();
+                assert_eq!(plan_for(&tree, &argv), Plan::Nothing, "{:?}", argv);
+            }
+        }
+    }
+
+    #[test]
+    fn dialog_area_does_not_overflow() {
+        let quirks = Quirks::from_args(&args(&[]));
+        let node = |r| Node::new(&serde_json::from_value(window(20, r, (0, 0, 0, 0))).expect("valid node"), true, quirks);
+        // 50000² is past i32::MAX.
+        assert!(node((0, 0, 1000, 1000)).is_dialog(Some(Area(50000, 50000))));
+        assert!(!node((0, 0, 50000, 50000)).is_dialog(Some(Area(1000, 1000))));
+        assert!(!node((0, 0, 50000, 50000)).is_dialog(Some(Area(50000, 50000))));
+        assert!(!node((0, 0, 1000, 1000)).is_dialog(None));
+    }
+
+    #[test]
+    fn cycle_floating_goes_to_the_bottom() {
+        let floating = |id, x| {
+            let mut w = window(id, (x, 100, 400, 300), (0, 0, 0, 0));
+            w["type"] = json!("floating_con");
+            w
+        };
+        let mut ws = workspace(1, "splith", vec![window(20, (0, 0, 1920, 1080), (0, 0, 0, 0))]);
+        // Bottom to top, unlike their positions.
+        ws["floating_nodes"] = json!([floating(30, 900), floating(31, 100), floating(32, 500)]);
+        let mut tree = tree(vec![ws]);
+
+        let cycle = ["focus", "cycle-floating"];
+        focus_on(&mut tree, 32);
+        assert_eq!(plan_for(&tree, &cycle), go_to(30, false));
+        assert_eq!(plan_for(&tree, &["focus", "cycle-floating", "2"]), go_to(31, false));
+        assert_eq!(plan_for(&tree, &["focus", "cycle-floating", "3"]), go_to(30, false));
+        // Even when the focused window isn't the topmost one.
+        focus_on(&mut tree, 31);
+        assert_eq!(plan_for(&tree, &cycle), go_to(30, false));
+        focus_on(&mut tree, 30);
+        assert_eq!(plan_for(&tree, &cycle), go_to(31, false));
+        focus_on(&mut tree, 20);
+        assert_eq!(plan_for(&tree, &cycle), go_to(30, false));
+    }
+
+    /// Three columns of three, one and two rows.
+    fn uneven_columns() -> Value {
+        tree(vec![workspace(1, "splith", vec![
+            con(21, "splitv", (0, 0, 640, 1080), (0, 0, 0, 0), vec![
+                window(30, (0, 0, 640, 360), (0, 0, 0, 0)),
+                window(31, (0, 360, 640, 360), (0, 0, 0, 0)),
+                window(32, (0, 720, 640, 360), (0, 0, 0, 0)),
+            ]),
+            window(40, (640, 0, 640, 1080), (0, 0, 0, 0)),
+            con(22, "splitv", (1280, 0, 640, 1080), (0, 0, 0, 0), vec![
+                window(50, (1280, 0, 640, 540), (0, 0, 0, 0)),
+                window(51, (1280, 540, 640, 540), (0, 0, 0, 0)),
+            ]),
+        ])])
+    }
+
+    #[test]
+    fn column_keeps_the_row() {
+        let mut tree = uneven_columns();
+        focus_on(&mut tree, 31);
+        assert_eq!(plan_for(&tree, &["column", "next"]), go_to(40, false));
+        assert_eq!(plan_for(&tree, &["column", "next", "2"]), go_to(51, false));
+        assert_eq!(plan_for(&tree, &["column", "prev"]), go_to(51, true));
+        // Columns with fewer rows clamp to their last one.
+        focus_on(&mut tree, 32);
+        assert_eq!(plan_for(&tree, &["column", "next", "2"]), go_to(51, false));
+        focus_on(&mut tree, 40);
+        assert_eq!(plan_for(&tree, &["column", "next"]), go_to(50, false));
+        assert_eq!(plan_for(&tree, &["column", "prev"]), go_to(30, false));
+    }
+
+    #[test]
+    fn column_wraps() {
+        let mut tree = uneven_columns();
+        focus_on(&mut tree, 51);
+        assert_eq!(plan_for(&tree, &["column", "next"]), go_to(31, true));
+        assert_eq!(plan_for(&tree, &["column", "next", "2"]), go_to(40, true));
+        assert_eq!(plan_for(&tree, &["column", "next", "3"]), Plan::Nothing);
+        assert_eq!(plan_for(&tree, &["--no-wrap", "column", "next"]), Plan::Nothing);
+        focus_on(&mut tree, 30);
+        assert_eq!(plan_for(&tree, &["column", "prev"]), go_to(50, true));
+        assert_eq!(plan_for(&tree, &["--no-wrap", "column", "prev"]), Plan::Nothing);
+        assert_eq!(plan_for(&tree, &["--no-wrap", "column", "next", "5"]), go_to(50, false));
+    }
+
+    #[test]
+    fn counts_past_the_end() {
+        // 20, 22, 23, 24 and 25.
+        let mut tree = tab_group();
+        focus_on(&mut tree, 22);
+        // Counts wrap around modulo the number of windows.
+        assert_eq!(plan_for(&tree, &["focus", "next", "4"]), go_to(20, true));
+        assert_eq!(plan_for(&tree, &["focus", "next", "7"]), go_to(24, false));
+        assert_eq!(plan_for(&tree, &["focus", "prev", "6"]), go_to(20, false));
+        assert_eq!(plan_for(&tree, &["focus", "next", "5"]), Plan::Nothing);
+        assert_eq!(plan_for(&tree, &["focus", "next", "100"]), Plan::Nothing);
+        // Or stop at the ends without wrapping.
+        assert_eq!(plan_for(&tree, &["--no-wrap", "focus", "next", "100"]), go_to(25, false));
+        assert_eq!(plan_for(&tree, &["--no-wrap", "focus", "prev", "100"]), go_to(20, false));
+        focus_on(&mut tree, 25);
+        assert_eq!(plan_for(&tree, &["--no-wrap", "focus", "next", "100"]), Plan::Nothing);
+
+        let Plan::Go { cmds, wrapped, .. } = plan_for(&tree, &["move", "next", "6"]) else { panic!("no move") };
+        assert_eq!((cmds, wrapped), (vec!["swap container with con_id 20".to_owned()], true));
+        assert_eq!(plan_for(&tree, &["move", "prev", "10"]), Plan::Nothing);
+    }
+
+    /// Workspaces 1 with windows 20 and 21, and 2 with 30 on the left output, 3 with 40 and 41, and
+    /// an empty 4 on the right one, listed first, and a scratchpad. The focus is on `window` on
+    /// workspace `ws`.
+    fn two_outputs(ws: i64, window_id: i64) -> Value {
+        let side_by_side = |id, x| window(id, (x, 0, 960, 1080), (0, 0, 0, 0));
+        let mut left = node(100, "output", "output", (0, 0, 1920, 1080), (0, 0, 0, 0), vec![
+            workspace(1, "splith", vec![side_by_side(20, 0), side_by_side(21, 960)]),
+            workspace(2, "splith", vec![window(30, (0, 0, 1920, 1080), (0, 0, 0, 0))]),
+        ]);
+        let mut right = node(110, "output", "output", (1920, 0, 1920, 1080), (0, 0, 0, 0), vec![
+            workspace(3, "splith", vec![side_by_side(40, 1920), side_by_side(41, 2880)]),
+            workspace(4, "splith", vec![]),
+        ]);
+        let mut scratch = node(120, "output", "output", (0, 0, 1920, 1080), (0, 0, 0, 0), vec![
+            workspace(5, "splith", vec![window(50, (0, 0, 1920, 1080), (0, 0, 0, 0))]),
+        ]);
+        scratch["name"] = json!("__i3");
+        let on_left = ws <= 2;
+        for output in [&mut left, &mut right] {
+            output["focus"].as_array_mut().unwrap().sort_by_key(|id| *id != json!(ws));
+        }
+        let outputs = if on_left { [100, 110, 120] } else { [110, 100, 120] };
+        let mut tree = node(101, "root", "splith", (0, 0, 3840, 1080), (0, 0, 0, 0), vec![scratch, right, left]);
+        tree["focus"] = json!(outputs);
+        focus_on(&mut tree, window_id);
+        tree
+    }
+
+    #[test]
+    fn parse_boundary() {
+        assert!(matches!(args(&["--boundary", "output", "status"]).boundary, Boundary::Output));
+        assert!(Args::from_args(&["sway-window-nav"], &["--boundary", "screen", "status"]).is_err());
+    }
+
+    #[test]
+    fn boundary_workspace() {
+        let tree = two_outputs(1, 21);
+        assert_eq!(plan_for(&tree, &["focus", "next"]), go_to(20, true));
+        assert_eq!(plan_for(&tree, &["--boundary", "workspace", "focus", "next"]), go_to(20, true));
+        assert_eq!(plan_for(&tree, &["--boundary", "workspace", "focus", "prev"]), go_to(20, false));
+        assert_eq!(plan_for(&two_outputs(2, 30), &["--boundary", "workspace", "focus", "next"]), Plan::Nothing);
+    }
+
+    #[test]
+    fn boundary_output() {
+        let next = ["--boundary", "output", "focus", "next"];
+        let prev = ["--boundary", "output", "focus", "prev"];
+        assert_eq!(plan_for(&two_outputs(1, 20), &next), go_to(21, false));
+        assert_eq!(plan_for(&two_outputs(1, 21), &next), go_to(30, false));
+        assert_eq!(plan_for(&two_outputs(2, 30), &next), go_to(20, true));
+        assert_eq!(plan_for(&two_outputs(2, 30), &prev), go_to(21, false));
+        assert_eq!(plan_for(&two_outputs(1, 20), &prev), go_to(30, true));
+        // The other workspace on the right output is empty, so it wraps around within the workspace.
+        assert_eq!(plan_for(&two_outputs(3, 41), &next), go_to(40, true));
+        assert_eq!(plan_for(&two_outputs(3, 40), &prev), go_to(41, true));
+        // Going past the ends doesn't wrap with --no-wrap, but still goes on to the next workspace.
+        assert_eq!(plan_for(&two_outputs(1, 21), &["--no-wrap", "--boundary", "output", "focus", "next"]), go_to(30, false));
+        assert_eq!(plan_for(&two_outputs(2, 30), &["--no-wrap", "--boundary", "output", "focus", "next"]), Plan::Nothing);
+    }
+
+    #[test]
+    fn boundary_global() {
+        let next = ["--boundary", "global", "focus", "next"];
+        let prev = ["--boundary", "global", "focus", "prev"];
+        assert_eq!(plan_for(&two_outputs(1, 21), &next), go_to(30, false));
+        assert_eq!(plan_for(&two_outputs(2, 30), &next), go_to(40, false));
+        assert_eq!(plan_for(&two_outputs(3, 40), &next), go_to(41, false));
+        // Past the empty workspace 4 and the scratchpad.
+        assert_eq!(plan_for(&two_outputs(3, 41), &next), go_to(20, true));
+        assert_eq!(plan_for(&two_outputs(1, 20), &prev), go_to(41, true));
+        assert_eq!(plan_for(&two_outputs(3, 40), &prev), go_to(30, false));
+        assert_eq!(plan_for(&two_outputs(2, 30), &prev), go_to(21, false));
+        // A count past the end lands on the first window of the next workspace.
+        assert_eq!(plan_for(&two_outputs(1, 20), &["--boundary", "global", "focus", "next", "5"]), go_to(30, false));
+        assert_eq!(plan_for(&two_outputs(3, 41), &["--no-wrap", "--boundary", "global", "focus", "next"]), Plan::Nothing);
+        assert_eq!(plan_for(&two_outputs(1, 20), &["--no-wrap", "--boundary", "global", "focus", "prev"]), Plan::Nothing);
+    }
+
+    /// Runs `f` with the interrupt signals blocked in this thread, as main has them, and takes
+    /// whichever of them was left pending, before letting them through again.
+    fn with_signals_blocked<T>(f: impl FnOnce() -> T) -> (T, Option<u32>) {
+        let signals = interrupt_signals();
+        signals.thread_block().expect("signals blocked");
+        let res = f();
+        let pending = SignalFd::with_flags(&signals, SfdFlags::SFD_NONBLOCK).expect("signalfd")
+            .read_signal().expect("read signal").map(|info| info.ssi_signo);
+        signals.thread_unblock().expect("signals unblocked");
+        (res, pending)
+    }
+
+    #[test]
+    fn flash_restores_after_duration() {
+        let mut log = Vec::new();
+        let started = time::Instant::now();
+        let (interrupted, pending) = with_signals_blocked(|| flash(&mut log, time::Duration::from_millis(20),
+                                                                     |log| { log.push("set"); Ok(()) }, |log| log.push("restore")));
+        assert!(!interrupted.unwrap());
+        assert!(started.elapsed() >= time::Duration::from_millis(20));
+        assert_eq!(log, ["set", "restore"]);
+        assert_eq!(pending, None);
+    }
+
+    #[test]
+    fn flash_restores_on_interrupt() {
+        let mut log = Vec::new();
+        let started = time::Instant::now();
+        let (interrupted, pending) = with_signals_blocked(|| flash(&mut log, time::Duration::from_secs(60), |log| {
+            log.push("set");
+            // Directed at this thread only, which has it blocked, like main does.
+            let this = pthread_self();
+            thread::spawn(move || {
+                thread::sleep(time::Duration::from_millis(20));
+                pthread_kill(this, Signal::SIGTERM).expect("signal sent");
+            });
+            Ok(())
+        }, |log| log.push("restore")));
+        assert!(interrupted.unwrap());
+        assert!(started.elapsed() < time::Duration::from_secs(60));
+        assert_eq!(log, ["set", "restore"]);
+        // Left pending, to take its course once let through.
+        assert_eq!(pending, Some(Signal::SIGTERM as u32));
+    }
+
+    #[test]
+    fn flash_not_restored_when_not_set() {
+        let mut log = Vec::new();
+        let (res, _) = with_signals_blocked(|| flash(&mut log, time::Duration::from_secs(60), |_| bail!("no sway"), |log| log.push("restore")));
+        assert!(res.is_err());
+        assert!(log.is_empty());
+    }
+
+    /// Times planning on a tree of 10 workspaces of 20 windows each, going through them with
+    /// --boundary global. Run with `cargo test --release -- --ignored --nocapture large_tree`.
+    #[test]
+    #[ignore]
+    fn large_tree_timing() {
+        let workspaces = (1..=10).map(|ws| {
+            let column = |c: i64| con(ws * 1000 + c, "splitv", (c as i32 * 960, 0, 960, 1080), (0, 0, 0, 0),
+                (0..10).map(|r| window(ws * 1000 + c * 100 + r, (c as i32 * 960, r as i32 * 108, 960, 108), (0, 0, 0, 0))).collect());
+            workspace(ws, "splith", vec![column(0), column(1)])
+        }).collect();
+        let mut tree = tree(workspaces);
+        focus_on(&mut tree, 1109);
+        let tree: swayipc::Node = serde_json::from_value(tree).expect("valid tree");
+
+        const RUNS: u32 = 1000;
+        for argv in [&["focus", "next"][..], &["--boundary", "global", "focus", "next"], &["status"]] {
+            // Parsed and cloned up front, to time only the planning.
+            let runs: Vec<_> = (0..RUNS).map(|_| {
+                let mut args = args(argv);
+                let command = args.command.take().expect("command");
+                (tree.clone(), args, command)
+            }).collect();
+            let started = time::Instant::now();
+            for (tree, args, command) in runs {
+                plan(tree, &args, command, None).unwrap();
+            }
+            println!("{:<40} {:?} per plan", argv.join(" "), started.elapsed() / RUNS);
+        }
+    }
 }
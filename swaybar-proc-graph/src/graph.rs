@@ -4,6 +4,11 @@ use std::collections::VecDeque;
 pub struct BrailleGraph {
     data: VecDeque<u8>,
     length: usize,
+    // How many of the values in data are real measurements, rather than the initial zeroes.
+    filled: usize,
+    // Longer history of the values for min/max, the graph itself only shows data.
+    archive: Option<VecDeque<u8>>,
+    archive_length: usize,
 }
 
 impl BrailleGraph {
@@ -11,6 +16,17 @@ impl BrailleGraph {
         Self {
             data: VecDeque::from(vec![0; length]),
             length,
+            filled: 0,
+            archive: None,
+            archive_length: 0,
+        }
+    }
+
+    pub fn with_archive_len(length: usize, archive_length: usize) -> Self {
+        Self {
+            archive: Some(VecDeque::with_capacity(archive_length)),
+            archive_length,
+            ..Self::new(length)
         }
     }
 
@@ -19,6 +35,40 @@ impl BrailleGraph {
             self.data.pop_front();
         }
         self.data.push_back(pct);
+        self.filled = (self.filled + 1).min(self.length);
+
+        if let Some(archive) = &mut self.archive {
+            if archive.len() >= self.archive_length {
+                archive.pop_front();
+            }
+            archive.push_back(pct);
+        }
+    }
+
+    fn history(&self) -> &VecDeque<u8> {
+        self.archive.as_ref().unwrap_or(&self.data)
+    }
+
+    pub fn min(&self) -> u8 {
+        self.history().iter().min().copied().unwrap_or(0)
+    }
+
+    pub fn max(&self) -> u8 {
+        self.history().iter().max().copied().unwrap_or(0)
+    }
+
+    /// Number of measurements currently shown in the graph.
+    pub fn filled(&self) -> usize {
+        self.filled
+    }
+
+    /// Min, average and max of the measurements shown in the graph, None before the first one.
+    pub fn window_stats(&self) -> Option<(u8, f64, u8)> {
+        let values = self.data.range(self.data.len() - self.filled..);
+        let min = values.clone().min().copied()?;
+        let max = values.clone().max().copied()?;
+        let avg = values.map(|&v| v as f64).sum::<f64>() / self.filled as f64;
+        Some((min, avg, max))
     }
 
     fn pct_thresholds(i: u8) -> u8 {
@@ -34,45 +84,101 @@ impl BrailleGraph {
             0
         }
     }
+
+    fn braille(next: u8, curr: u8) -> char {
+        match (Self::pct_thresholds(next), Self::pct_thresholds(curr)) {
+            (0, 0) => '\u{2800}', // '⠀'
+            (0, 1) => '\u{2880}', // '⢀'
+            (0, 2) => '\u{28A0}', // '⢠'
+            (0, 3) => '\u{28B0}', // '⢰'
+            (0, 4) => '\u{28B8}', // '⢸'
+            (1, 0) => '\u{2840}', // '⡀'
+            (1, 1) => '\u{28C0}', // '⣀'
+            (1, 2) => '\u{28E0}', // '⣠'
+            (1, 3) => '\u{28F0}', // '⣰'
+            (1, 4) => '\u{28F8}', // '⣸'
+            (2, 0) => '\u{2844}', // '⡄'
+            (2, 1) => '\u{28C4}', // '⣄'
+            (2, 2) => '\u{28E4}', // '⣤'
+            (2, 3) => '\u{28F4}', // '⣴'
+            (2, 4) => '\u{28FC}', // '⣼'
+            (3, 0) => '\u{2846}', // '⡆'
+            (3, 1) => '\u{28C6}', // '⣆'
+            (3, 2) => '\u{28E6}', // '⣦'
+            (3, 3) => '\u{28F6}', // '⣶'
+            (3, 4) => '\u{28FE}', // '⣾'
+            (4, 0) => '\u{2847}', // '⡇'
+            (4, 1) => '\u{28C7}', // '⣇'
+            (4, 2) => '\u{28E7}', // '⣧'
+            (4, 3) => '\u{28F7}', // '⣷'
+            (4, 4) => '\u{28FF}', // '⣿'
+            _ => unreachable!("WHOAHOA!")
+        }
+    }
+
+    /// The graph with each column colored by its percentage with Pango markup, through
+    /// `gradient_color`. Like the alternate form, leaves out the initial zeroes unless `all`.
+    pub fn gradient(&self, all: bool) -> String {
+        let start = if all { 0 } else { self.data.len() - self.filled };
+        self.data.range(start..)
+            .map(|&pct| {
+                let [r, g, b] = gradient_color(pct);
+                format!("<span foreground='#{:02x}{:02x}{:02x}'>{}</span>", r, g, b, Self::braille(pct, pct))
+            })
+            .collect()
+    }
 }
 
+/// Colors a gradient goes through, evenly spaced from 0% to 100%.
+const GRADIENT: [[u8; 3]; 3] = [[0x00, 0xff, 0x00], [0xff, 0xff, 0x00], [0xff, 0x00, 0x00]];
+
+/// Color of `pct`, interpolated linearly from green at 0% through yellow at 50% to red at 100%.
+pub fn gradient_color(pct: u8) -> [u8; 3] {
+    let pos = pct.min(100) as f64 / 100.0 * (GRADIENT.len() - 1) as f64;
+    let i = (pos as usize).min(GRADIENT.len() - 2);
+    let t = pos - i as f64;
+    let (from, to) = (GRADIENT[i], GRADIENT[i + 1]);
+    [0, 1, 2].map(|c| (from[c] as f64 + (to[c] as f64 - from[c] as f64) * t).round() as u8)
+}
+
+/// The alternate form (`{:#}`) leaves out the initial zeroes, growing with the measurements.
 impl fmt::Display for BrailleGraph {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut iter = self.data.iter().peekable();
+        let start = if f.alternate() { self.data.len() - self.filled } else { 0 };
+        let mut iter = self.data.range(start..).peekable();
         while iter.peek().is_some() {
             let next = **iter.peek().unwrap();
             let curr = *iter.next().unwrap();
 
-            let c = match (BrailleGraph::pct_thresholds(next), BrailleGraph::pct_thresholds(curr)) {
-                (0, 0) => '\u{2800}', // '⠀'
-                (0, 1) => '\u{2880}', // '⢀'
-                (0, 2) => '\u{28A0}', // '⢠'
-                (0, 3) => '\u{28B0}', // '⢰'
-                (0, 4) => '\u{28B8}', // '⢸'
-                (1, 0) => '\u{2840}', // '⡀'
-                (1, 1) => '\u{28C0}', // '⣀'
-                (1, 2) => '\u{28E0}', // '⣠'
-                (1, 3) => '\u{28F0}', // '⣰'
-                (1, 4) => '\u{28F8}', // '⣸'
-                (2, 0) => '\u{2844}', // '⡄'
-                (2, 1) => '\u{28C4}', // '⣄'
-                (2, 2) => '\u{28E4}', // '⣤'
-                (2, 3) => '\u{28F4}', // '⣴'
-                (2, 4) => '\u{28FC}', // '⣼'
-                (3, 0) => '\u{2846}', // '⡆'
-                (3, 1) => '\u{28C6}', // '⣆'
-                (3, 2) => '\u{28E6}', // '⣦'
-                (3, 3) => '\u{28F6}', // '⣶'
-                (3, 4) => '\u{28FE}', // '⣾'
-                (4, 0) => '\u{2847}', // '⡇'
-                (4, 1) => '\u{28C7}', // '⣇'
-                (4, 2) => '\u{28E7}', // '⣧'
-                (4, 3) => '\u{28F7}', // '⣷'
-                (4, 4) => '\u{28FF}', // '⣿'
-                _ => unreachable!("WHOAHOA!")
-            };
-            write!(f, "{}", c)?;
+            write!(f, "{}", BrailleGraph::braille(next, curr))?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradient_interpolation() {
+        assert_eq!(gradient_color(0), [0x00, 0xff, 0x00]);
+        assert_eq!(gradient_color(25), [0x80, 0xff, 0x00]);
+        assert_eq!(gradient_color(50), [0xff, 0xff, 0x00]);
+        assert_eq!(gradient_color(75), [0xff, 0x80, 0x00]);
+        assert_eq!(gradient_color(100), [0xff, 0x00, 0x00]);
+        assert_eq!(gradient_color(255), [0xff, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn gradient_columns() {
+        let mut graph = BrailleGraph::new(3);
+        graph.update(50);
+        graph.update(100);
+        assert_eq!(
+            graph.gradient(false),
+            "<span foreground='#ffff00'>\u{28E4}</span><span foreground='#ff0000'>\u{28FF}</span>"
+        );
+        assert_eq!(graph.gradient(true).matches("<span foreground='#00ff00'>\u{2800}</span>").count(), 1);
+    }
+}
@@ -0,0 +1,337 @@
+//! Time since the last input, from the compositor through the ext-idle-notify-v1 Wayland protocol.
+//! The few requests it takes pass no file descriptors, so just enough of the wire format is here
+//! to make them, without the Wayland libraries.
+use std::{env, io, thread, time};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::sources::{Measurement, Source};
+
+/// How long without input before the compositor reports idling, from which on idle time counts.
+const IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+// The ids of the objects, in the order they're created. The display is always 1.
+const DISPLAY: u32 = 1;
+const REGISTRY: u32 = 2;
+const SYNC_CALLBACK: u32 = 3;
+const SEAT: u32 = 4;
+const IDLE_NOTIFIER: u32 = 5;
+const IDLE_NOTIFICATION: u32 = 6;
+
+/// A message in the Wayland wire format: the object id, the size and opcode, then the arguments,
+/// each padded to 32 bits.
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(object: u32, opcode: u16) -> Self {
+        let mut buf = Vec::with_capacity(32);
+        buf.extend(object.to_ne_bytes());
+        buf.extend(u32::from(opcode).to_ne_bytes());
+        Self(buf)
+    }
+
+    fn uint(mut self, value: u32) -> Self {
+        self.0.extend(value.to_ne_bytes());
+        self
+    }
+
+    fn string(mut self, s: &str) -> Self {
+        self = self.uint(s.len() as u32 + 1);
+        self.0.extend(s.as_bytes());
+        self.0.push(0);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+        self
+    }
+
+    /// The message with its size filled in.
+    fn finish(mut self) -> Vec<u8> {
+        let size = self.0.len() as u32;
+        let opcode = u32::from_ne_bytes([self.0[4], self.0[5], self.0[6], self.0[7]]);
+        self.0[4..8].copy_from_slice(&(size << 16 | opcode).to_ne_bytes());
+        self.0
+    }
+}
+
+/// An event read off the socket, with its arguments still in the wire format.
+struct Event {
+    object: u32,
+    opcode: u16,
+    args: Vec<u8>,
+}
+
+impl Event {
+    fn read(r: &mut impl Read) -> io::Result<Self> {
+        let mut header = [0; 8];
+        r.read_exact(&mut header)?;
+        let object = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let word = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+        let size = (word >> 16) as usize;
+        if size < header.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Wayland message shorter than its header"));
+        }
+        let mut args = vec![0; size - header.len()];
+        r.read_exact(&mut args)?;
+        Ok(Self { object, opcode: word as u16, args })
+    }
+
+    fn args(&self) -> Args<'_> {
+        Args(&self.args)
+    }
+}
+
+/// Reads the arguments of an event in order.
+struct Args<'a>(&'a [u8]);
+
+impl Args<'_> {
+    fn uint(&mut self) -> Result<u32> {
+        let (value, rest) = self.0.split_first_chunk::<4>().ok_or_else(|| anyhow!("Wayland event too short"))?;
+        self.0 = rest;
+        Ok(u32::from_ne_bytes(*value))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.uint()? as usize;
+        let padded = len.next_multiple_of(4);
+        if padded > self.0.len() {
+            bail!("Wayland event too short");
+        }
+        // Without the terminating NUL.
+        let s = String::from_utf8_lossy(&self.0[..len.saturating_sub(1)]).into_owned();
+        self.0 = &self.0[padded..];
+        Ok(s)
+    }
+}
+
+/// The error the compositor sent with a wl_display.error event.
+fn display_error(event: &Event) -> anyhow::Error {
+    let mut args = event.args();
+    match (args.uint(), args.uint(), args.string()) {
+        (Ok(object), Ok(code), Ok(message)) => anyhow!("Wayland error {} on object {}: {}", code, object, message),
+        _ => anyhow!("Wayland error"),
+    }
+}
+
+/// The compositor's socket, `$WAYLAND_DISPLAY` in `$XDG_RUNTIME_DIR` unless it's a path itself.
+fn socket_path() -> Result<PathBuf> {
+    let display = PathBuf::from(env::var_os("WAYLAND_DISPLAY").unwrap_or_else(|| "wayland-0".into()));
+    if display.is_absolute() {
+        return Ok(display);
+    }
+    let runtime_dir = env::var_os("XDG_RUNTIME_DIR").ok_or_else(|| anyhow!("XDG_RUNTIME_DIR is not set"))?;
+    Ok(PathBuf::from(runtime_dir).join(display))
+}
+
+enum State {
+    Active,
+    IdleSince(time::Instant),
+    /// The connection to the compositor failed.
+    Lost(String),
+}
+
+/// Idle time as a level, full at `max`, kept up to date by a thread reading the idle notifications.
+pub struct IdleTime {
+    state: Arc<Mutex<State>>,
+    max: time::Duration,
+}
+
+impl IdleTime {
+    /// Connects to the compositor, failing if it doesn't support ext-idle-notify-v1.
+    pub fn connect(max: time::Duration) -> Result<Self> {
+        let path = socket_path()?;
+        let stream = UnixStream::connect(&path).with_context(|| format!("Wayland display {}", path.display()))?;
+        Self::with_stream(stream, max)
+    }
+
+    fn with_stream(mut stream: UnixStream, max: time::Duration) -> Result<Self> {
+        // wl_display.get_registry, and wl_display.sync to know when all the globals were listed.
+        stream.write_all(&Message::new(DISPLAY, 1).uint(REGISTRY).finish())?;
+        stream.write_all(&Message::new(DISPLAY, 0).uint(SYNC_CALLBACK).finish())?;
+
+        let (mut seat, mut notifier) = (None, None);
+        loop {
+            let event = Event::read(&mut stream).context("Reading the Wayland globals")?;
+            match (event.object, event.opcode) {
+                (DISPLAY, 0) => return Err(display_error(&event)),
+                // wl_registry.global
+                (REGISTRY, 0) => {
+                    let mut args = event.args();
+                    let (name, interface) = (args.uint()?, args.string()?);
+                    match interface.as_str() {
+                        "wl_seat" if seat.is_none() => seat = Some(name),
+                        "ext_idle_notifier_v1" => notifier = Some(name),
+                        _ => {},
+                    }
+                },
+                (SYNC_CALLBACK, 0) => break,
+                _ => {},
+            }
+        }
+        let notifier = notifier.ok_or_else(|| anyhow!("The compositor doesn't support ext-idle-notify-v1 (sway does from 1.8 on)"))?;
+        let seat = seat.ok_or_else(|| anyhow!("The compositor has no seat"))?;
+
+        // wl_registry.bind, and ext_idle_notifier_v1.get_idle_notification.
+        stream.write_all(&Message::new(REGISTRY, 0).uint(seat).string("wl_seat").uint(1).uint(SEAT).finish())?;
+        stream.write_all(&Message::new(REGISTRY, 0).uint(notifier).string("ext_idle_notifier_v1").uint(1).uint(IDLE_NOTIFIER).finish())?;
+        let timeout = IDLE_TIMEOUT.as_millis() as u32;
+        stream.write_all(&Message::new(IDLE_NOTIFIER, 1).uint(IDLE_NOTIFICATION).uint(timeout).uint(SEAT).finish())?;
+
+        let state = Arc::new(Mutex::new(State::Active));
+        let statec = Arc::clone(&state);
+        thread::spawn(move || loop {
+            let new_state = match Event::read(&mut stream) {
+                // ext_idle_notification_v1.idled, sent once there was no input for IDLE_TIMEOUT.
+                Ok(Event { object: IDLE_NOTIFICATION, opcode: 0, .. }) => {
+                    let now = time::Instant::now();
+                    State::IdleSince(now.checked_sub(IDLE_TIMEOUT).unwrap_or(now))
+                },
+                // ext_idle_notification_v1.resumed
+                Ok(Event { object: IDLE_NOTIFICATION, opcode: 1, .. }) => State::Active,
+                Ok(event @ Event { object: DISPLAY, opcode: 0, .. }) => State::Lost(display_error(&event).to_string()),
+                Ok(_) => continue,
+                Err(e) => State::Lost(e.to_string()),
+            };
+            let lost = matches!(new_state, State::Lost(_));
+            *statec.lock().expect("Idle state lock poisoned") = new_state;
+            if lost {
+                return;
+            }
+        });
+        Ok(Self { state, max })
+    }
+}
+
+impl Source for IdleTime {
+    /// The idle time in milliseconds as the used amount, out of `max`.
+    fn sample(&mut self) -> Result<Measurement> {
+        let idle = match &*self.state.lock().expect("Idle state lock poisoned") {
+            State::Active => time::Duration::ZERO,
+            State::IdleSince(since) => since.elapsed(),
+            State::Lost(e) => bail!("Lost the Wayland connection: {}", e),
+        };
+        let max = self.max.as_millis() as u64;
+        Ok(Measurement { free: max.saturating_sub(idle.as_millis() as u64), total: max })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the compositor on the other end of `stream`, with the globals `interfaces`, then
+    /// sends the events `after` once the idle notification was asked for. Hands back the stream to
+    /// send more on.
+    fn compositor(mut stream: UnixStream, interfaces: &'static [&'static str], after: &'static [u16]) -> thread::JoinHandle<UnixStream> {
+        thread::spawn(move || {
+            for _ in 0..2 {
+                Event::read(&mut stream).unwrap();
+            }
+            for (name, interface) in (1..).zip(interfaces) {
+                stream.write_all(&Message::new(REGISTRY, 0).uint(name).string(interface).uint(1).finish()).unwrap();
+            }
+            stream.write_all(&Message::new(SYNC_CALLBACK, 0).uint(0).finish()).unwrap();
+            if !interfaces.contains(&"ext_idle_notifier_v1") {
+                return stream;
+            }
+
+            let binds: Vec<_> = (0..2).map(|_| Event::read(&mut stream).unwrap()).collect();
+            let mut args = binds[1].args();
+            args.uint().unwrap();
+            assert_eq!(args.string().unwrap(), "ext_idle_notifier_v1");
+            assert_eq!([args.uint().unwrap(), args.uint().unwrap()], [1, IDLE_NOTIFIER]);
+            let get = Event::read(&mut stream).unwrap();
+            assert_eq!((get.object, get.opcode), (IDLE_NOTIFIER, 1));
+            let mut args = get.args();
+            assert_eq!([args.uint().unwrap(), args.uint().unwrap(), args.uint().unwrap()], [IDLE_NOTIFICATION, 1000, SEAT]);
+
+            for &opcode in after {
+                stream.write_all(&Message::new(IDLE_NOTIFICATION, opcode).finish()).unwrap();
+            }
+            stream
+        })
+    }
+
+    /// Samples until the idle time is at least `at_least`, or gives up.
+    fn idle_ms(idle: &mut IdleTime, at_least: u64) -> Result<u64> {
+        for _ in 0..100 {
+            let m = idle.sample()?;
+            if m.total - m.free >= at_least {
+                return Ok(m.total - m.free);
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let m = idle.sample()?;
+        Ok(m.total - m.free)
+    }
+
+    #[test]
+    fn message_format() {
+        let msg = Message::new(REGISTRY, 0).uint(7).string("wl_seat").uint(1).uint(SEAT).finish();
+        assert_eq!(msg.len(), 8 + 4 + 4 + 8 + 4 + 4);
+        let event = Event::read(&mut msg.as_slice()).unwrap();
+        assert_eq!((event.object, event.opcode), (REGISTRY, 0));
+        let mut args = event.args();
+        assert_eq!(args.uint().unwrap(), 7);
+        assert_eq!(args.string().unwrap(), "wl_seat");
+        assert_eq!([args.uint().unwrap(), args.uint().unwrap()], [1, SEAT]);
+        assert!(args.uint().is_err());
+    }
+
+    #[test]
+    fn idle_and_resumed() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let compositor = compositor(server, &["wl_compositor", "wl_seat", "ext_idle_notifier_v1"], &[0]);
+        let mut idle = IdleTime::with_stream(client, time::Duration::from_secs(10)).unwrap();
+        let mut server = compositor.join().unwrap();
+        // Counted from when the input stopped, IDLE_TIMEOUT before the notification.
+        let ms = idle_ms(&mut idle, 1000).unwrap();
+        assert!((1000..10_000).contains(&ms), "{}", ms);
+
+        server.write_all(&Message::new(IDLE_NOTIFICATION, 1).finish()).unwrap();
+        for _ in 0..100 {
+            if idle.sample().unwrap().free == 10_000 {
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        panic!("still idle after resumed");
+    }
+
+    #[test]
+    fn full_at_max() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let compositor = compositor(server, &["wl_seat", "ext_idle_notifier_v1"], &[0]);
+        let mut idle = IdleTime::with_stream(client, time::Duration::from_millis(500)).unwrap();
+        let _server = compositor.join().unwrap();
+        idle_ms(&mut idle, 500).unwrap();
+        let m = idle.sample().unwrap();
+        assert_eq!((m.free, m.total), (0, 500));
+    }
+
+    #[test]
+    fn lost_connection() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let compositor = compositor(server, &["wl_seat", "ext_idle_notifier_v1"], &[]);
+        let mut idle = IdleTime::with_stream(client, time::Duration::from_secs(10)).unwrap();
+        drop(compositor.join().unwrap());
+        for _ in 0..100 {
+            if let Err(e) = idle.sample() {
+                assert!(e.to_string().contains("Lost the Wayland connection"), "{}", e);
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        panic!("hang up went unnoticed");
+    }
+
+    #[test]
+    fn unsupported_compositor() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let compositor = compositor(server, &["wl_compositor", "wl_seat"], &[]);
+        let e = IdleTime::with_stream(client, time::Duration::from_secs(10)).err().unwrap();
+        compositor.join().unwrap();
+        assert!(e.to_string().contains("ext-idle-notify-v1"), "{}", e);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt::Write as _;
+use std::io;
+use anyhow::{anyhow, Result};
+use swayipc::{Connection, Event, EventType, Input, InputChange};
+
+use crate::{escape_json, Format, Output};
+
+/// The identifier of the keyboard to follow: `device`, or else the first keyboard of `inputs`,
+/// given as their identifiers and types.
+fn followed_device<'a>(mut inputs: impl Iterator<Item = (&'a str, &'a str)>, device: Option<&str>) -> Option<String> {
+    match device {
+        Some(d) => Some(d.to_owned()),
+        None => inputs.find(|&(_, input_type)| input_type == "keyboard").map(|(id, _)| id.to_owned()),
+    }
+}
+
+/// Formats the layout `name` at `index`, without the trailing newline.
+fn line(out: &Output, name: &str, index: i32) -> String {
+    let text = format!("{}{}{}", out.prefix, name, out.suffix);
+    if let Format::Text = out.format {
+        return text;
+    }
+
+    let mut line = format!("{{\"text\": \"{}\"", escape_json(&text));
+    if out.tooltip {
+        let _ = write!(line, ", \"tooltip\": \"Keyboard layout {}\"", escape_json(name));
+    }
+    let _ = write!(line, ", \"class\": \"layout-{}\"}}", index);
+    line
+}
+
+fn write_layout(w: &mut impl io::Write, out: &Output, input: &Input) -> io::Result<()> {
+    let name = input.xkb_active_layout_name.as_deref().unwrap_or_default();
+    out.write_line(w, &line(out, name, input.xkb_active_layout_index.unwrap_or(0)))
+}
+
+/// Writes out the active keyboard layout of `device`, and again whenever it changes.
+pub fn run(device: Option<&str>, out: &Output) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout_handle = stdout.lock();
+
+    // Subscribed first, so that no change goes missing in between.
+    let events = Connection::new()?.subscribe([EventType::Input])?;
+    let inputs = Connection::new()?.get_inputs()?;
+    // Every keyboard reports its own layout, so settle on one of them for good.
+    let device = followed_device(inputs.iter().map(|i| (i.identifier.as_str(), i.input_type.as_str())), device)
+        .ok_or_else(|| anyhow!("No keyboard found, pick one with --device"))?;
+    if let Some(input) = inputs.iter().find(|i| i.identifier == device) {
+        write_layout(&mut stdout_handle, out, input)?;
+    }
+
+    for event in events {
+        if let Event::Input(e) = event? {
+            if matches!(e.change, InputChange::XkbLayout | InputChange::XkbKeymap) && e.input.identifier == device {
+                write_layout(&mut stdout_handle, out, &e.input)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::output;
+
+    #[test]
+    fn json() {
+        let out = Output { prefix: "\u{f11c} ".to_owned(), ..output(Format::Json) };
+        assert_eq!(
+            line(&out, "English (US)", 1),
+            "{\"text\": \"\u{f11c} English (US)\", \"tooltip\": \"Keyboard layout English (US)\", \"class\": \"layout-1\"}"
+        );
+    }
+
+    #[test]
+    fn json_without_tooltip() {
+        let out = Output { tooltip: false, ..output(Format::Json) };
+        assert_eq!(line(&out, "Finnish \"fi\"", 0), "{\"text\": \"Finnish \\\"fi\\\"\", \"class\": \"layout-0\"}");
+    }
+
+    #[test]
+    fn follows_the_first_keyboard() {
+        let inputs = [("1:1:Power_Button", "switch"), ("1:1:AT_Keyboard", "keyboard"), ("2:2:Other_Keyboard", "keyboard")];
+        assert_eq!(followed_device(inputs.into_iter(), None).as_deref(), Some("1:1:AT_Keyboard"));
+        assert_eq!(followed_device(inputs.into_iter(), Some("2:2:Other_Keyboard")).as_deref(), Some("2:2:Other_Keyboard"));
+        // Followed once it turns up.
+        assert_eq!(followed_device(inputs.into_iter(), Some("3:3:Later")).as_deref(), Some("3:3:Later"));
+        assert_eq!(followed_device(inputs[..1].iter().copied(), None), None);
+    }
+
+    #[test]
+    fn text() {
+        let out = Output { suffix: " ⌨".to_owned(), ..output(Format::Text) };
+        assert_eq!(line(&out, "Finnish", 0), "Finnish ⌨");
+    }
+}
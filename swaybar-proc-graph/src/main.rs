@@ -1,10 +1,14 @@
 use std::{fs, str, time, thread};
-use std::io::{self, Write};
-use anyhow::Result;
+use std::io::{self, IsTerminal, Write};
+use anyhow::{bail, Context, Result};
 use argh::FromArgs;
 
 mod sources;
 mod graph;
+mod layout;
+mod top;
+#[cfg(feature = "wayland")]
+mod idle;
 use crate::graph::BrailleGraph;
 use crate::sources::*;
 
@@ -13,21 +17,60 @@ use crate::sources::*;
 enum GraphType {
     Cpu(SubCommandCpu),
     Memory(SubCommandMemory),
+    Pool(SubCommandPool),
     #[cfg(feature = "nvidia")]
     NvGpu(SubCommandNvGpu),
     #[cfg(feature = "nvidia")]
     NvVram(SubCommandNvVram),
+    #[cfg(feature = "nvidia")]
+    NvList(SubCommandNvList),
+    Layout(SubCommandLayout),
+    #[cfg(feature = "wayland")]
+    Idle(SubCommandIdle),
+    Test(SubCommandTest),
 }
 
 /// CPU usage graph
 #[derive(FromArgs)]
 #[argh(subcommand, name = "cpu")]
-struct SubCommandCpu {}
+struct SubCommandCpu {
+    /// report usage below this percentage as 0, to hide flicker from timer resolution (default: 0)
+    #[argh(option, default = "0.0")]
+    idle_threshold: f64,
+    /// whether time waiting for I/O counts as idle or busy (default: busy)
+    #[argh(option, default = "Iowait::Busy")]
+    count_iowait: Iowait,
+    /// print the number of CPUs found in /proc/stat and exit
+    #[argh(switch)]
+    print_cpu_count: bool,
+    /// with --print-cpu-count, count physical cores, hyperthreads of the same core as one
+    #[argh(switch)]
+    physical_cores: bool,
+    /// add the process that used the most CPU time to the tooltip, scanning /proc on every update
+    #[argh(switch)]
+    top_process: bool,
+}
 
 /// Memory usage graph
 #[derive(FromArgs)]
 #[argh(subcommand, name = "memory")]
-struct SubCommandMemory {}
+struct SubCommandMemory {
+    /// add the process using the most memory to the tooltip, scanning /proc on every update
+    #[argh(switch)]
+    top_process: bool,
+}
+
+/// ZFS pool or Btrfs filesystem space usage graph
+#[derive(FromArgs)]
+#[argh(subcommand, name = "pool")]
+struct SubCommandPool {
+    /// pool type, zfs or btrfs
+    #[argh(option, long = "type")]
+    pool_type: PoolType,
+    /// ZFS pool name, or Btrfs mount point
+    #[argh(positional)]
+    target: String,
+}
 
 /// Nvidia GPU usage graph
 #[cfg(feature = "nvidia")]
@@ -49,124 +92,684 @@ struct SubCommandNvVram {
     gpu_index: u32,
 }
 
+/// List the Nvidia GPUs with their index, name and memory, to pick a --gpu-index from
+#[cfg(feature = "nvidia")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "nvlist")]
+struct SubCommandNvList {}
+
+/// Keyboard layout indicator, updated as the layout changes instead of every interval
+#[derive(FromArgs)]
+#[argh(subcommand, name = "layout")]
+struct SubCommandLayout {
+    /// input device identifier to follow (default: the first keyboard found at startup)
+    #[argh(option)]
+    device: Option<String>,
+}
+
+/// Time since the last input, from the compositor's ext-idle-notify-v1 protocol
+#[cfg(feature = "wayland")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "idle")]
+struct SubCommandIdle {
+    /// idle time in seconds at which the graph is full (default: 300)
+    #[argh(option, default = "time::Duration::from_secs(300)", from_str_fn(nonzero_dur_from_str_secs))]
+    max: time::Duration,
+}
+
+/// Check that each data source can be read, and exit
+#[derive(FromArgs)]
+#[argh(subcommand, name = "test")]
+struct SubCommandTest {
+    /// select GPU by index (starts from 0)
+    #[cfg(feature = "nvidia")]
+    #[argh(option, default = "0")]
+    gpu_index: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Text,
+    Prometheus,
+}
+
+impl str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "text" => Ok(Format::Text),
+            "prometheus" => Ok(Format::Prometheus),
+            _ => Err("accepts only json, text or prometheus".to_owned()),
+        }
+    }
+}
+
+impl GraphType {
+    /// Graph length and update interval to use when not given on the command line.
+    fn defaults(&self) -> (usize, time::Duration) {
+        match self {
+            // Space usage changes slowly, and measuring it runs an external command.
+            GraphType::Pool(_) => (10, time::Duration::from_secs(30)),
+            _ => (10, time::Duration::from_secs(1)),
+        }
+    }
+
+    /// Value of the type label in --format prometheus output.
+    fn metric_type(&self) -> &'static str {
+        match self {
+            GraphType::Cpu(_) => "cpu",
+            GraphType::Memory(_) => "memory",
+            GraphType::Pool(_) => "pool",
+            #[cfg(feature = "nvidia")]
+            GraphType::NvGpu(_) => "nvgpu",
+            #[cfg(feature = "nvidia")]
+            GraphType::NvVram(_) => "nvvram",
+            #[cfg(feature = "nvidia")]
+            GraphType::NvList(_) => "nvlist",
+            GraphType::Layout(_) => "layout",
+            #[cfg(feature = "wayland")]
+            GraphType::Idle(_) => "idle",
+            GraphType::Test(_) => "test",
+        }
+    }
+}
+
 fn dur_from_str_secs(s: &str) -> Result<time::Duration, String> {
     s.parse()
         .map(time::Duration::from_secs)
         .map_err(|_| "value not a valid integer".to_owned())
 }
 
+#[cfg(feature = "wayland")]
+fn nonzero_dur_from_str_secs(s: &str) -> Result<time::Duration, String> {
+    dur_from_str_secs(s)
+        .and_then(|dur| if dur.is_zero() { Err("value not greater than 0".to_owned()) } else { Ok(dur) })
+}
+
+fn ema_alpha_from_str(s: &str) -> Result<f64, String> {
+    s.parse()
+        .ok()
+        .filter(|&alpha| alpha > 0.0 && alpha <= 1.0)
+        .ok_or_else(|| "value not a number greater than 0 and at most 1".to_owned())
+}
+
 #[derive(FromArgs)]
 /// Print out CPU, memory, or Nvidia GPU usage graph in Waybar compatible JSON format.
 struct Args {
-    /// graph length in characters
-    #[argh(option, default = "10")]
-    len: usize,
-    /// update interval in seconds
-    #[argh(option, short = 'i', default = "time::Duration::from_secs(1)", from_str_fn(dur_from_str_secs))]
-    interval: time::Duration,
+    /// graph length in characters (default: depends on the graph type, usually 10)
+    #[argh(option)]
+    len: Option<usize>,
+    /// number of past values to keep for the min/max shown in the tooltip (default: 0, disabled)
+    #[argh(option, default = "0")]
+    history_len: usize,
+    /// update interval in seconds (default: depends on the graph type, usually 1)
+    #[argh(option, short = 'i', from_str_fn(dur_from_str_secs))]
+    interval: Option<time::Duration>,
+    /// text to prepend to the graph, e.g. an icon (counts toward the visible width)
+    #[argh(option, default = "String::new()")]
+    prefix_text: String,
+    /// text to append to the graph, e.g. a unit (counts toward the visible width)
+    #[argh(option, default = "String::new()")]
+    suffix_text: String,
+    /// average the last N measurements, or with "weighted" or "weighted:N" give the recent ones
+    /// more weight (default: 1, no smoothing)
+    #[argh(option)]
+    smooth: Option<Smoothing>,
+    /// smooth exponentially instead, weighing each measurement ALPHA (0 < ALPHA <= 1) against the
+    /// previous value shown
+    #[argh(option, arg_name = "alpha", from_str_fn(ema_alpha_from_str))]
+    ema: Option<f64>,
+    /// clip percentages below this before graphing and reporting them (default: 0)
+    #[argh(option, default = "0.0")]
+    clamp_min: f64,
+    /// clip percentages above this before graphing and reporting them (default: 100)
+    #[argh(option, default = "100.0")]
+    clamp_max: f64,
+    /// show only the measurements taken so far, growing the graph up to --len, instead of the full
+    /// length graph from the start
+    #[argh(switch)]
+    no_pad: bool,
+    /// color each column of the graph by its percentage, from green through yellow to red, with
+    /// Pango markup in the JSON text
+    #[argh(switch)]
+    gradient: bool,
+    /// add the min, average and max of the values in the graph to the tooltip
+    #[argh(switch)]
+    tooltip_stats: bool,
+    /// leave the tooltip out of the JSON
+    #[argh(switch)]
+    no_tooltip: bool,
+    /// output format, Waybar compatible json, the plain graph as text for e.g. tmux, or prometheus
+    /// metric lines for a textfile collector (default: json, or text rewriting a single line when
+    /// stdout is a terminal)
+    #[argh(option)]
+    format: Option<Format>,
+    /// don't end each line of output with a newline, for bars that split the JSON objects by
+    /// themselves
+    #[argh(switch)]
+    no_newline: bool,
+    /// with --format text, follow the graph with the percentage
+    #[argh(switch)]
+    show_percentage: bool,
+    /// print only the percentage as a bare number, instead of JSON (only with the text format)
+    #[argh(switch)]
+    output_only_percentage: bool,
+    /// buffer size in bytes for reading /proc files, 0 to size it after the file (default: 8192)
+    #[argh(option, default = "DEFAULT_BUF_CAPACITY")]
+    proc_buf_size: usize,
     /// graph type
     #[argh(subcommand)]
     graph_type: GraphType,
 }
 
-fn main() -> Result<()> {
-    let Args { graph_type, interval, len: graph_len } = argh::from_env();
+/// Formats used/total in MiB, GiB or TiB, from a measurement in units of `unit` bytes.
+fn format_usage(curr: Measurement, unit: u64) -> String {
+    let used = (curr.total - curr.free) as f64 * unit as f64;
+    let total = curr.total as f64 * unit as f64;
+    if total / (1024_u32.pow(2) as f64) < 1024.0 {
+        let div = 1024_u32.pow(2) as f64;
+        format!("{:.1}/{:.1} MiB", used / div, total / div)
+    } else if total / (1024_u32.pow(3) as f64) < 1024.0 {
+        let div = 1024_u32.pow(3) as f64;
+        format!("{:.1}/{:.1} GiB", used / div, total / div)
+    } else {
+        let div = 1024_u64.pow(4) as f64;
+        format!("{:.1}/{:.1} TiB", used / div, total / div)
+    }
+}
+
+/// Escapes `s` for use inside a JSON string.
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes out the graph and measurements in the selected format.
+struct Output {
+    graph: BrailleGraph,
+    graph_len: usize,
+    /// Whether to show the full length graph from the start, rather than grow it as measurements
+    /// come in.
+    pad: bool,
+    /// Whether to color the columns of the JSON graph by their percentage.
+    gradient: bool,
+    history: bool,
+    prefix: String,
+    suffix: String,
+    format: Format,
+    /// Whether to end each measurement with a newline.
+    newline: bool,
+    /// Whether to write each measurement over the previous one, for watching it in a terminal.
+    overwrite: bool,
+    /// The type label of --format prometheus lines.
+    metric_type: &'static str,
+    show_percentage: bool,
+    tooltip: bool,
+    only_percentage: bool,
+    /// Percentages below this are shown as 0.
+    idle_threshold: f64,
+    /// Range the percentages are clipped to.
+    clamp: (f64, f64),
+    /// The update interval, when the graph window summary is shown in the tooltip.
+    stats_interval: Option<time::Duration>,
+}
+
+impl Output {
+    fn graph_text(&self) -> String {
+        if self.pad {
+            format!("{:\u{2800}>pad$}", self.graph, pad=self.graph_len)
+        } else {
+            format!("{:#}", self.graph)
+        }
+    }
+
+    /// Formats a measurement, without the trailing newline.
+    fn line(&mut self, pct: f64, tooltip: &str) -> String {
+        use std::fmt::Write as _;
+
+        let pct = pct.clamp(self.clamp.0, self.clamp.1);
+        self.graph.update(pct as u8);
+
+        if self.only_percentage {
+            return format!("{:.1}", pct);
+        }
+
+        if let Format::Prometheus = self.format {
+            return format!("swaybar_proc_graph_percentage{{type=\"{}\"}} {:.1}", self.metric_type, pct);
+        }
+
+        if let Format::Text = self.format {
+            let mut line = format!("{}{}{}", self.prefix, self.graph_text(), self.suffix);
+            if self.show_percentage {
+                let _ = write!(line, " {:.0}%", pct);
+            }
+            return line;
+        }
+
+        // The markup only uses single quotes, so it needs no escaping.
+        let graph = if self.gradient { self.graph.gradient(self.pad) } else { self.graph_text() };
+        let mut line = format!(
+            "{{\"percentage\": {:.0}, \"text\": \"{}{}{}\"",
+            pct, escape_json(&self.prefix), graph, escape_json(&self.suffix)
+        );
+        if !self.tooltip {
+            line.push('}');
+            return line;
+        }
+
+        let _ = write!(line, ", \"tooltip\": \"{}", tooltip);
+        if self.history {
+            let _ = write!(line, ", min {}% max {}%", self.graph.min(), self.graph.max());
+        }
+        if let (Some(interval), Some((min, avg, max))) = (self.stats_interval, self.graph.window_stats()) {
+            let window = interval * self.graph.filled() as u32;
+            let _ = write!(line, " | {}s min {}% avg {:.0}% max {}%", window.as_secs(), min, avg, max);
+        }
+        line.push_str("\"}");
+        line
+    }
+
+    fn write(&mut self, w: &mut impl Write, pct: f64, tooltip: &str) -> io::Result<()> {
+        let line = self.line(pct, tooltip);
+        self.write_line(w, &line)
+    }
+
+    /// Writes out a formatted line, ended as selected.
+    fn write_line(&self, w: &mut impl Write, line: &str) -> io::Result<()> {
+        if self.overwrite {
+            // Clear the rest of the line, in case the previous one was longer.
+            write!(w, "\r{}\x1b[K", line)?;
+            w.flush()
+        } else if self.newline {
+            writeln!(w, "{}", line)
+        } else {
+            // Stdout is only flushed on newlines by itself.
+            write!(w, "{}", line)?;
+            w.flush()
+        }
+    }
+}
+
+/// The top process part of a tooltip, empty without one.
+fn top_suffix(top: Option<String>) -> String {
+    top.map(|name| format!(", top: {}", escape_json(&name))).unwrap_or_default()
+}
 
+/// How many intervals may pass between two polls before the measurement is considered to span a
+/// suspend.
+const RESUME_GAP_FACTOR: u32 = 5;
+
+/// Measures `stat` at `now`, or None if the last poll was so long before that the measurement
+/// spans a suspend/resume.
+fn poll<S: StatTaker>(
+    stat: &mut S, out: &Output, last_poll: &mut time::SystemTime, now: time::SystemTime, interval: time::Duration
+) -> Result<Option<f64>> {
+    let mut pct = stat.measure()?;
+    if pct < out.idle_threshold {
+        pct = 0.0;
+    }
+
+    // After a suspend/resume the measurement covers the whole time asleep, which shows up as a
+    // spike for the sources that measure a delta. Measuring already re-seeded them, so skip it,
+    // and start the smoothing over so that it doesn't linger in the average either.
+    let gap = now.duration_since(*last_poll).unwrap_or_default();
+    *last_poll = now;
+    if gap > interval * RESUME_GAP_FACTOR {
+        stat.reset();
+        return Ok(None);
+    }
+    Ok(Some(pct))
+}
+
+/// Measures `stat` every `interval` forever, writing out each measurement with the tooltip from
+/// `tooltip`.
+fn run<S: StatTaker>(
+    mut stat: S, interval: time::Duration, out: &mut Output, mut tooltip: impl FnMut(f64, Measurement) -> String
+) -> Result<()> {
     let stdout = io::stdout();
     let mut stdout_handle = stdout.lock();
-    let mut graph = BrailleGraph::new(graph_len);
+    // Wall-clock time, as Instant doesn't advance during suspend.
+    let mut last_poll = time::SystemTime::now();
+
+    loop {
+        let Some(pct) = poll(&mut stat, out, &mut last_poll, time::SystemTime::now(), interval)? else {
+            thread::sleep(interval);
+            continue;
+        };
+
+        let tooltip = if out.tooltip { tooltip(pct, stat.measurement()) } else { String::new() };
+        out.write(&mut stdout_handle, pct, &tooltip)?;
+        thread::sleep(interval);
+    }
+}
+
+/// Prints whether the source `name` could be measured, returning true if it could.
+fn report_source(name: &str, res: Result<f64>) -> bool {
+    match res {
+        Ok(pct) => {
+            println!("{:<14} available ({:.1}%)", name, pct);
+            true
+        },
+        Err(e) => {
+            println!("{:<14} unavailable: {:#}", name, e);
+            false
+        },
+    }
+}
+
+/// Measures each data source once, printing a table of which ones are available.
+fn self_test(proc_buf_size: usize, subargs: SubCommandTest) -> Result<()> {
+    let mut ok = report_source("/proc/stat", (|| {
+        let f = fs::File::open("/proc/stat").context("/proc/stat")?;
+        let capacity = if proc_buf_size == 0 { ProcStat::detect_capacity(&f) } else { proc_buf_size };
+        Stat::new(ProcStat::with_capacity(f, capacity, Iowait::Busy)).measure()
+    })());
+    ok &= report_source("/proc/meminfo", (|| {
+        let f = fs::File::open("/proc/meminfo").context("/proc/meminfo")?;
+        let capacity = if proc_buf_size == 0 { ProcMeminfo::detect_capacity(&f) } else { proc_buf_size };
+        Stat::new(ProcMeminfo::with_capacity(f, capacity)).measure()
+    })());
+    // Built in by default, so only a failure on a machine that has the driver counts.
+    #[cfg(feature = "nvidia")]
+    match nvml_available() {
+        Ok(()) => {
+            ok &= report_source("NVML GPU", NvmlGpu::new(subargs.gpu_index).and_then(|s| Stat::new(s).measure()));
+            ok &= report_source("NVML VRAM", NvmlVram::new(subargs.gpu_index).and_then(|s| Stat::new(s).measure()));
+        },
+        Err(e) => println!("{:<14} skipped: {:#}", "NVML", e),
+    }
+    #[cfg(not(feature = "nvidia"))]
+    let SubCommandTest {} = subargs;
+    // Only there to check under a Wayland session.
+    #[cfg(feature = "wayland")]
+    match std::env::var_os("WAYLAND_DISPLAY") {
+        Some(_) => ok &= report_source("Wayland idle", idle::IdleTime::connect(time::Duration::from_secs(300)).and_then(|s| Stat::new(s).measure())),
+        None => println!("{:<14} skipped: WAYLAND_DISPLAY is not set", "Wayland idle"),
+    }
+
+    if !ok {
+        bail!("some data sources are unavailable");
+    }
+    Ok(())
+}
+
+/// The format to write in, given the one asked for, and whether to overwrite each line with the
+/// next.
+fn output_format(format: Option<Format>, terminal: bool) -> (Format, bool) {
+    match format {
+        Some(format) => (format, false),
+        // Show the graph itself to someone trying it out in a terminal, rather than a stream of JSON.
+        None if terminal => (Format::Text, true),
+        None => (Format::Json, false),
+    }
+}
+
+fn main() -> Result<()> {
+    let Args {
+        graph_type, interval, len: graph_len, smooth, ema, clamp_min, clamp_max, no_pad, history_len,
+        prefix_text: prefix, suffix_text: suffix, no_tooltip, gradient, tooltip_stats, format, no_newline,
+        show_percentage, output_only_percentage, proc_buf_size
+    } = argh::from_env();
+    let (default_len, default_interval) = graph_type.defaults();
+    let graph_len = graph_len.unwrap_or(default_len);
+    let interval = interval.unwrap_or(default_interval);
+    let smoothing = match (smooth, ema) {
+        (Some(_), Some(_)) => bail!("--smooth and --ema can't be used together"),
+        (_, Some(alpha)) => Smoothing::Exponential(alpha),
+        (smooth, None) => smooth.unwrap_or(Smoothing::Simple(1)),
+    };
+    if clamp_min.is_nan() || clamp_max.is_nan() || clamp_min > clamp_max {
+        bail!("--clamp-min must not be greater than --clamp-max");
+    }
+    if gradient && matches!(format, Some(Format::Text | Format::Prometheus)) {
+        bail!("--gradient can only be used with --format json");
+    }
+    if output_only_percentage && !matches!(format, None | Some(Format::Text)) {
+        bail!("--output-only-percentage can only be used with --format text");
+    }
+    let (format, overwrite) = output_format(format, io::stdout().is_terminal());
+
+    let mut out = Output {
+        graph: if history_len > 0 {
+            BrailleGraph::with_archive_len(graph_len, history_len)
+        } else {
+            BrailleGraph::new(graph_len)
+        },
+        graph_len,
+        pad: !no_pad,
+        gradient,
+        history: history_len > 0,
+        prefix,
+        suffix,
+        format,
+        newline: !no_newline,
+        overwrite,
+        metric_type: graph_type.metric_type(),
+        show_percentage,
+        tooltip: !no_tooltip,
+        only_percentage: output_only_percentage,
+        idle_threshold: 0.0,
+        clamp: (clamp_min, clamp_max),
+        stats_interval: tooltip_stats.then_some(interval),
+    };
 
     match graph_type {
+        GraphType::Test(subargs) => self_test(proc_buf_size, subargs),
         #[cfg(feature = "nvidia")]
-        GraphType::NvGpu(subargs) => {
-            let mut stat = NvmlGpu::new(subargs.gpu_index)?;
-
-            loop {
-                let pct = stat.measure()?;
-                graph.update(pct as u8);
-                writeln!(
-                    stdout_handle,
-                    "{{\"percentage\": {:.0}, \"text\": \"{:\u{2800}>pad$}\", \"tooltip\": \"GPU usage {:.0}%\"}}",
-                    pct, graph, pct, pad=graph_len
-                )?;
-                thread::sleep(interval);
+        GraphType::NvList(_) => {
+            let gpus = list_gpus()?;
+            let name_width = gpus.iter().map(|g| g.name.len()).max().unwrap_or(0).max("NAME".len());
+            println!("{:<5}  {:<name_width$}  {:>10}", "INDEX", "NAME", "MEMORY");
+            for gpu in gpus {
+                println!("{:<5}  {:<name_width$}  {:>6} MiB", gpu.index, gpu.name, gpu.memory / 1024_u64.pow(2));
             }
+            Ok(())
+        },
+        GraphType::Layout(subargs) => {
+            if out.only_percentage || matches!(out.format, Format::Prometheus) {
+                bail!("layout has no percentage for --output-only-percentage or --format prometheus");
+            }
+            layout::run(subargs.device.as_deref(), &out)
+        },
+        #[cfg(feature = "wayland")]
+        GraphType::Idle(subargs) => {
+            let stat = idle::IdleTime::connect(subargs.max)?;
+            // Measured in milliseconds.
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |_, curr| format!("Idle for {}s", (curr.total - curr.free) / 1000))
+        },
+        #[cfg(feature = "nvidia")]
+        GraphType::NvGpu(subargs) => {
+            let stat = NvmlGpu::new(subargs.gpu_index)?;
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |pct, _| format!("GPU usage {:.0}%", pct))
         },
         #[cfg(feature = "nvidia")]
         GraphType::NvVram(subargs) => {
-            let mut stat = NvmlVram::new(subargs.gpu_index)?;
-
-            loop {
-                let pct = stat.measure()?;
-                let curr = stat.measurement();
-                graph.update(pct as u8);
-
-                write!(
-                    stdout_handle,
-                    "{{\"percentage\": {:.0}, \"text\": \"{:\u{2800}>pad$}\", \"tooltip\": \"GPU VRAM usage ",
-                    pct, graph, pad=graph_len
-                )?;
-                // NVML MemoryInfo values are in bytes.
-                if curr.total as f64 / (1024_u32.pow(2) as f64) < 1024.0 {
-                    let div = 1024_u32.pow(2) as f64;
-                    write!(stdout_handle, "{:.1}/{:.1} MiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                } else if curr.total as f64 / (1024_u32.pow(3) as f64) < 1024.0 {
-                    let div = 1024_u32.pow(3) as f64;
-                    write!(stdout_handle, "{:.1}/{:.1} GiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                } else {
-                    let div = 1024_u64.pow(4) as f64;
-                    write!(stdout_handle, "{:.1}/{:.1} TiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                }?;
-                writeln!(stdout_handle, " ({:.2}%)\"}}", pct)?;
-
-                thread::sleep(interval);
-            }
+            let stat = NvmlVram::new(subargs.gpu_index)?;
+            // NVML MemoryInfo values are in bytes.
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |pct, curr| format!("GPU VRAM usage {} ({:.2}%)", format_usage(curr, 1), pct))
         },
-        GraphType::Memory(_) => {
+        GraphType::Pool(subargs) => {
+            let stat = PoolUsage::new(subargs.pool_type, subargs.target);
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |pct, curr| format!("Pool usage {} ({:.2}%)", format_usage(curr, 1), pct))
+        },
+        GraphType::Memory(subargs) => {
             let f = fs::File::open("/proc/meminfo")?;
-            let mut stat = ProcMeminfo::new(f);
-
-            loop {
-                let pct = stat.measure()?;
-                let curr = stat.measurement();
-                graph.update(pct as u8);
-
-                write!(
-                    stdout_handle,
-                    "{{\"percentage\": {:.0}, \"text\": \"{:\u{2800}>pad$}\", \"tooltip\": \"Memory usage ",
-                    pct, graph, pad=graph_len
-                )?;
-                // /proc/meminfo values are in KiBs.
-                if curr.total as f64 / 1024_f64 < 1024.0 {
-                    let div = 1024_f64;
-                    write!(stdout_handle, "{:.1}/{:.1} MiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                } else if curr.total as f64 / (1024_u32.pow(2) as f64) < 1024.0 {
-                    let div = 1024_u32.pow(2) as f64;
-                    write!(stdout_handle, "{:.1}/{:.1} GiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                } else {
-                    let div = 1024_u32.pow(3) as f64;
-                    write!(stdout_handle, "{:.1}/{:.1} TiB", (curr.total - curr.free) as f64 / div, curr.total as f64 / div)
-                }?;
-                writeln!(stdout_handle, " ({:.2}%)\"}}", pct)?;
-
-                thread::sleep(interval);
-            }
+            let capacity = if proc_buf_size == 0 { ProcMeminfo::detect_capacity(&f) } else { proc_buf_size };
+            let stat = ProcMeminfo::with_capacity(f, capacity);
+            // /proc/meminfo values are in KiBs.
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |pct, curr| {
+                let top = if subargs.top_process { top::top_memory() } else { None };
+                format!("Memory usage {} ({:.2}%){}", format_usage(curr, 1024), pct, top_suffix(top))
+            })
         },
-        GraphType::Cpu(_) => {
+        GraphType::Cpu(subargs) => {
+            out.idle_threshold = subargs.idle_threshold;
             let f = fs::File::open("/proc/stat")?;
-            let mut stat = ProcStat::new(f);
-
-            loop {
-                let pct = stat.measure()?;
-                graph.update(pct as u8);
+            let capacity = if proc_buf_size == 0 { ProcStat::detect_capacity(&f) } else { proc_buf_size };
+            let mut stat = ProcStat::with_capacity(f, capacity, subargs.count_iowait);
+            if subargs.physical_cores && !subargs.print_cpu_count {
+                bail!("--physical-cores only applies to --print-cpu-count");
+            }
+            if subargs.print_cpu_count {
+                let cpus = stat.cpus()?;
+                let cores = subargs.physical_cores
+                    .then(|| physical_cores(SYS_CPU.as_ref(), &cpus))
+                    .flatten()
+                    .map_or(cpus.len(), |cores| cores.into_iter().max().map_or(0, |max| max + 1));
+                println!("{}", cores);
+                return Ok(());
+            }
+            let mut top_cpu = subargs.top_process.then(top::TopCpu::default);
+            run(Smoothed::new(Stat::new(stat), smoothing), interval, &mut out, |pct, _| {
+                let top = top_cpu.as_mut().and_then(top::TopCpu::update);
+                format!("CPU usage {:.2}%{}", pct, top_suffix(top))
+            })
+        }
+    }
+}
 
-                writeln!(
-                    stdout_handle, "{{\"percentage\": {:.0}, \"text\": \"{:\u{2800}>pad$}\", \"tooltip\": \"CPU usage {:.2}%\"}}",
-                    pct, graph, pct, pad=graph_len
-                )?;
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::sources::tests::fixed;
 
-                thread::sleep(interval);
-            }
+    pub(crate) fn output(format: Format) -> Output {
+        Output {
+            graph: BrailleGraph::new(4),
+            graph_len: 4,
+            pad: true,
+            gradient: false,
+            history: false,
+            prefix: String::new(),
+            suffix: String::new(),
+            format,
+            newline: true,
+            overwrite: false,
+            metric_type: "cpu",
+            show_percentage: false,
+            tooltip: true,
+            only_percentage: false,
+            idle_threshold: 0.0,
+            clamp: (0.0, 100.0),
+            stats_interval: None,
         }
     }
+
+    /// Polls `stat` once at each of `secs` seconds, one second apart being the interval.
+    fn poll_at<S: StatTaker>(stat: &mut S, out: &Output, secs: &[u64]) -> Vec<Option<f64>> {
+        let start = time::SystemTime::UNIX_EPOCH;
+        let mut last_poll = start;
+        secs.iter()
+            .map(|&s| poll(stat, out, &mut last_poll, start + time::Duration::from_secs(s), time::Duration::from_secs(1)).unwrap())
+            .collect()
+    }
+
+    /// The text field of a --format json line.
+    fn json_text(line: &str) -> &str {
+        let start = line.find("\"text\": \"").unwrap() + "\"text\": \"".len();
+        let end = start + line[start..].find('"').unwrap();
+        &line[start..end]
+    }
+
+    #[test]
+    fn multibyte_prefix_and_suffix() {
+        let mut out = Output { prefix: "\u{f2db} ".to_owned(), suffix: "°C…".to_owned(), ..output(Format::Json) };
+        out.line(10.0, "");
+        let line = out.line(90.0, "");
+        let text = json_text(&line);
+        let graph = text.strip_prefix("\u{f2db} ").unwrap().strip_suffix("°C…").unwrap();
+        assert_eq!(graph, "\u{2800}\u{2800}\u{2800}\u{28FF}");
+        assert_eq!(graph, out.graph_text());
+
+        out.format = Format::Text;
+        assert_eq!(out.line(90.0, ""), format!("\u{f2db} {}°C…", out.graph_text()));
+    }
+
+    #[test]
+    fn prefix_and_suffix_are_escaped() {
+        let mut out = Output { prefix: "\"\u{2800}\\".to_owned(), suffix: "\n".to_owned(), ..output(Format::Json) };
+        let line = out.line(0.0, "");
+        assert!(line.contains("\"text\": \"\\\"\u{2800}\\\\\u{2800}\u{2800}\u{2800}\u{2800}\\n\""), "{}", line);
+    }
+
+    /// The JSON line of a single CPU measurement of `pct`.
+    fn cpu_json(pct: f64, idle_threshold: f64) -> String {
+        let mut out = Output { idle_threshold, ..output(Format::Json) };
+        let pct = poll_at(&mut fixed(&[pct]), &out, &[1])[0].unwrap();
+        out.line(pct, &format!("CPU usage {:.2}%", pct))
+    }
+
+    #[test]
+    fn idle_threshold() {
+        assert_eq!(
+            cpu_json(0.3, 1.0),
+            "{\"percentage\": 0, \"text\": \"\u{2800}\u{2800}\u{2800}\u{2800}\", \"tooltip\": \"CPU usage 0.00%\"}"
+        );
+        assert!(cpu_json(0.3, 0.0).ends_with("\"tooltip\": \"CPU usage 0.30%\"}"));
+        assert!(cpu_json(1.0, 1.0).ends_with("\"tooltip\": \"CPU usage 1.00%\"}"));
+    }
+
+    #[test]
+    fn gradient() {
+        let mut out = Output { gradient: true, pad: false, ..output(Format::Json) };
+        let line = out.line(50.0, "");
+        assert!(line.contains("\"text\": \"<span foreground='#ffff00'>\u{28E4}</span>\""), "{}", line);
+    }
+
+    #[test]
+    fn terminal_output() {
+        assert!(matches!(output_format(None, true), (Format::Text, true)));
+        assert!(matches!(output_format(None, false), (Format::Json, false)));
+        assert!(matches!(output_format(Some(Format::Json), true), (Format::Json, false)));
+        assert!(matches!(output_format(Some(Format::Text), false), (Format::Text, false)));
+
+        let mut out = Output { overwrite: true, ..output(Format::Text) };
+        let mut w = Vec::new();
+        out.write(&mut w, 90.0, "").unwrap();
+        out.write(&mut w, 90.0, "").unwrap();
+        let line = "\r\u{2800}\u{2800}\u{28FF}\u{28FF}\x1b[K";
+        assert_eq!(String::from_utf8(w).unwrap(), format!("\r\u{2800}\u{2800}\u{2800}\u{28FF}\x1b[K{}", line));
+    }
+
+    #[test]
+    fn only_percentage() {
+        let mut out = Output { only_percentage: true, ..output(Format::Text) };
+        assert_eq!(out.line(72.44, "tooltip"), "72.4");
+    }
+
+    #[test]
+    fn resume_gap_skips_spike() {
+        let out = output(Format::Json);
+        let mut stat = fixed(&[10.0, 20.0, 95.0, 30.0]);
+        // Asleep between the 2nd and 3rd poll, for longer than RESUME_GAP_FACTOR intervals.
+        assert_eq!(poll_at(&mut stat, &out, &[1, 2, 600, 601]), [Some(10.0), Some(20.0), None, Some(30.0)]);
+    }
+
+    #[test]
+    fn resume_gap_resets_smoothing() {
+        let out = output(Format::Json);
+        let mut stat = Smoothed::new(fixed(&[10.0, 20.0, 95.0, 30.0]), Smoothing::Simple(2));
+        assert_eq!(poll_at(&mut stat, &out, &[1, 2, 600, 601]), [Some(10.0), Some(15.0), None, Some(30.0)]);
+    }
+
+    #[test]
+    fn short_gap_is_kept() {
+        let out = output(Format::Json);
+        let mut stat = fixed(&[10.0, 20.0]);
+        assert_eq!(poll_at(&mut stat, &out, &[1, 1 + RESUME_GAP_FACTOR as u64]), [Some(10.0), Some(20.0)]);
+    }
 }
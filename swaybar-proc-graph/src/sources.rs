@@ -1,6 +1,23 @@
-use std::{fs, time, thread};
+use std::{fs, str, time, thread};
+use std::collections::VecDeque;
 use std::io::{self, BufRead, Seek};
-use anyhow::Result;
+use std::path::Path;
+use std::process::Command;
+use anyhow::{anyhow, bail, Context, Result};
+
+/// Default capacity of the buffers used for reading /proc files.
+pub const DEFAULT_BUF_CAPACITY: usize = 8192;
+
+/// Size the whole file `f` fits in, rounded up to the next power of two. Most /proc files report
+/// a size of 0, so those get read through once instead. Leaves `f` at the start.
+fn detect_capacity(mut f: &fs::File) -> usize {
+    let len = match f.metadata() {
+        Ok(m) if m.len() > 0 => m.len(),
+        _ => io::copy(&mut f, &mut io::sink()).unwrap_or(0),
+    };
+    let _ = f.seek(io::SeekFrom::Start(0));
+    (len as usize).max(DEFAULT_BUF_CAPACITY).next_power_of_two()
+}
 
 #[derive(Default, Copy, Clone)]
 pub struct Measurement {
@@ -11,66 +28,233 @@ pub struct Measurement {
 pub trait StatTaker {
     fn measurement(&self) -> Measurement;
     fn measure(&mut self) -> Result<f64>;
+    /// Forgets the measurements taken so far, e.g. after a gap in them.
+    fn reset(&mut self) {}
+}
+
+/// Whether a source reports how much is in use right now, like memory, or cumulative amounts that
+/// only grow, like CPU time, of which the change between two samples is what gets graphed.
+#[derive(Clone, Copy)]
+pub enum StatKind {
+    Level,
+    Counter,
+}
+
+/// Something to take free and total amounts from, measured as a percentage by `Stat`.
+pub trait Source {
+    const KIND: StatKind = StatKind::Level;
+
+    fn sample(&mut self) -> Result<Measurement>;
+}
+
+/// Measures the usage percentage of a source, from its levels or the rate of its counters.
+pub struct Stat<S> {
+    source: S,
+    /// The latest sample, or for counters the change since the one before.
+    curr: Measurement,
+    prev: Measurement,
+}
+
+impl<S: Source> Stat<S> {
+    pub fn new(mut source: S) -> Self {
+        let mut prev = Measurement::default();
+        // Try to initialize prev value, so that the first measurement doesn't cover all of uptime.
+        if let StatKind::Counter = S::KIND {
+            if let Ok(val) = source.sample() {
+                prev = val;
+                thread::sleep(time::Duration::from_millis(100));
+            }
+        }
+        Self { source, curr: Measurement::default(), prev }
+    }
+}
+
+impl<S: Source> StatTaker for Stat<S> {
+    fn measurement(&self) -> Measurement {
+        self.curr
+    }
+
+    fn measure(&mut self) -> Result<f64> {
+        let val = self.source.sample()?;
+        self.curr = match S::KIND {
+            StatKind::Level => val,
+            StatKind::Counter => Measurement {
+                free: val.free.saturating_sub(self.prev.free),
+                total: val.total.saturating_sub(self.prev.total),
+            },
+        };
+        self.prev = val;
+
+        let pct = 100.0 * ((self.curr.total as f64 - self.curr.free as f64) / self.curr.total as f64);
+        Ok(pct)
+    }
+}
+
+/// How to smooth the measurements: averaging the last ones equally, or with more weight on the
+/// recent ones, or exponentially with the given alpha.
+#[derive(Clone, Copy)]
+pub enum Smoothing {
+    Simple(usize),
+    Weighted(usize),
+    Exponential(f64),
+}
+
+impl str::FromStr for Smoothing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || "accepts only N, weighted or weighted:N, with N > 0".to_owned();
+        let samples = |n: &str| n.parse().ok().filter(|&n| n > 0).ok_or_else(err);
+        match s.split_once(':') {
+            Some(("weighted", n)) => Ok(Smoothing::Weighted(samples(n)?)),
+            None if s == "weighted" => Ok(Smoothing::Weighted(DEFAULT_WEIGHTED_SAMPLES)),
+            None => Ok(Smoothing::Simple(samples(s)?)),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// Samples averaged by a plain `weighted` smoothing.
+const DEFAULT_WEIGHTED_SAMPLES: usize = 5;
+
+enum Smoother {
+    /// Moving average over the last samples. The weights go from 1 for the oldest to N for the
+    /// newest sample with weighted smoothing, and are all 1 otherwise.
+    MovingAverage { samples: VecDeque<f64>, weights: Vec<f64> },
+    /// Exponential moving average, each sample weighing alpha against the previous average.
+    Exponential { alpha: f64, prev: Option<f64> },
+}
+
+/// The measurements of `S`, smoothed.
+pub struct Smoothed<S> {
+    inner: S,
+    smoother: Smoother,
+}
+
+impl<S: StatTaker> Smoothed<S> {
+    pub fn new(inner: S, smoothing: Smoothing) -> Self {
+        let weights: Vec<f64> = match smoothing {
+            Smoothing::Simple(n) => vec![1.0; n],
+            Smoothing::Weighted(n) => (1..=n).map(|w| w as f64).collect(),
+            Smoothing::Exponential(alpha) => {
+                return Self { inner, smoother: Smoother::Exponential { alpha, prev: None } };
+            },
+        };
+        let samples = VecDeque::with_capacity(weights.len());
+        Self { inner, smoother: Smoother::MovingAverage { samples, weights } }
+    }
+}
+
+impl<S: StatTaker> StatTaker for Smoothed<S> {
+    fn measurement(&self) -> Measurement {
+        self.inner.measurement()
+    }
+
+    fn measure(&mut self) -> Result<f64> {
+        let pct = self.inner.measure()?;
+        match &mut self.smoother {
+            Smoother::MovingAverage { samples, weights } => {
+                if samples.len() >= weights.len() {
+                    samples.pop_front();
+                }
+                samples.push_back(pct);
+
+                // Until there are N samples, only as many of the lowest weights are used.
+                let weights = &weights[..samples.len()];
+                let sum: f64 = samples.iter().zip(weights).map(|(s, w)| s * w).sum();
+                Ok(sum / weights.iter().sum::<f64>())
+            },
+            Smoother::Exponential { alpha, prev } => {
+                // The first sample has nothing to be averaged with.
+                let ema = prev.map_or(pct, |prev| *alpha * pct + (1.0 - *alpha) * prev);
+                *prev = Some(ema);
+                Ok(ema)
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        match &mut self.smoother {
+            Smoother::MovingAverage { samples, .. } => samples.clear(),
+            Smoother::Exponential { prev, .. } => *prev = None,
+        }
+    }
 }
 
 #[cfg(feature = "nvidia")]
 pub use self::nvml::*;
 #[cfg(feature = "nvidia")]
 mod nvml {
-    use anyhow::Result;
-    use super::{StatTaker, Measurement};
+    use anyhow::{anyhow, Result};
+    use super::{Source, Measurement};
     use {
-        nvml_wrapper::Nvml,
+        nvml_wrapper::{Nvml, error::NvmlError},
         once_cell::sync::Lazy,
     };
 
-    static NVML: Lazy<Nvml> = Lazy::new(|| Nvml::init().expect("NVML init failed"));
+    static NVML: Lazy<Result<Nvml, NvmlError>> = Lazy::new(Nvml::init);
+
+    fn nvml() -> Result<&'static Nvml> {
+        NVML.as_ref().map_err(|e| anyhow!("NVML init failed: {}", e))
+    }
+
+    /// Whether NVML could be initialized, failing without the Nvidia driver.
+    pub fn nvml_available() -> Result<()> {
+        nvml().map(|_| ())
+    }
+
+    /// A GPU as NVML lists it, with its memory in bytes.
+    pub struct GpuInfo {
+        pub index: u32,
+        pub name: String,
+        pub memory: u64,
+    }
+
+    pub fn list_gpus() -> Result<Vec<GpuInfo>> {
+        let nvml = nvml()?;
+        (0..nvml.device_count()?)
+            .map(|index| {
+                let device = nvml.device_by_index(index)?;
+                Ok(GpuInfo { index, name: device.name()?, memory: device.memory_info()?.total })
+            })
+            .collect()
+    }
 
     pub struct NvmlGpu {
         device: nvml_wrapper::Device<'static>,
-        pct: u32,
     }
 
     impl NvmlGpu {
         pub fn new(gpu_index: u32) -> Result<Self> {
-            let device = NVML.device_by_index(gpu_index)?;
-            Ok(Self { device, pct: 0 })
+            let device = nvml()?.device_by_index(gpu_index)?;
+            Ok(Self { device })
         }
     }
 
-    impl StatTaker for NvmlGpu {
-        fn measurement(&self) -> Measurement {
-            Measurement { free: 100_u32.saturating_sub(self.pct) as u64, total: 100 }
-        }
-
-        fn measure(&mut self) -> Result<f64> {
-            self.pct = self.device.utilization_rates().map(|util| util.gpu)?;
-            Ok(self.pct as f64)
+    impl Source for NvmlGpu {
+        fn sample(&mut self) -> Result<Measurement> {
+            let pct = self.device.utilization_rates().map(|util| util.gpu)?;
+            Ok(Measurement { free: 100_u32.saturating_sub(pct) as u64, total: 100 })
         }
     }
 
     pub struct NvmlVram {
         device: nvml_wrapper::Device<'static>,
-        curr: Measurement,
     }
 
     impl NvmlVram {
         pub fn new(gpu_index: u32) -> Result<Self> {
-            let device = NVML.device_by_index(gpu_index)?;
-            Ok(Self { device, curr: Measurement::default() })
+            let device = nvml()?.device_by_index(gpu_index)?;
+            Ok(Self { device })
         }
     }
 
-    impl StatTaker for NvmlVram {
-        fn measurement(&self) -> Measurement {
-            self.curr
-        }
-
-        fn measure(&mut self) -> Result<f64> {
+    impl Source for NvmlVram {
+        fn sample(&mut self) -> Result<Measurement> {
             let mem = self.device.memory_info()?;
-            self.curr = Measurement { free: mem.free, total: mem.total };
-            let pct = 100.0 * (mem.used as f64 / mem.total as f64);
-            Ok(pct)
+            // Counting what the driver reserves as free, like the usage always did.
+            Ok(Measurement { free: mem.total.saturating_sub(mem.used), total: mem.total })
         }
     }
 }
@@ -78,17 +262,19 @@ mod nvml {
 pub struct ProcMeminfo {
     reader: io::BufReader<fs::File>,
     buf: String,
-    curr: Measurement,
 }
 
 impl ProcMeminfo {
     const SEEK_TO_START: io::SeekFrom = io::SeekFrom::Start(0);
 
-    pub fn new(f: fs::File) -> Self {
+    pub fn detect_capacity(f: &fs::File) -> usize {
+        detect_capacity(f)
+    }
+
+    pub fn with_capacity(f: fs::File, capacity: usize) -> Self {
         Self {
-            reader: io::BufReader::with_capacity(8192, f),
-            buf: String::with_capacity(8192),
-            curr: Measurement::default(),
+            reader: io::BufReader::with_capacity(capacity, f),
+            buf: String::with_capacity(capacity),
         }
     }
 
@@ -120,46 +306,56 @@ impl ProcMeminfo {
     }
 }
 
-impl StatTaker for ProcMeminfo {
-    fn measurement(&self) -> Measurement {
-        self.curr
+impl Source for ProcMeminfo {
+    fn sample(&mut self) -> Result<Measurement> {
+        Self::parse_proc_meminfo(&mut self.reader, &mut self.buf)
     }
+}
 
-    fn measure(&mut self) -> Result<f64> {
-        self.curr = Self::parse_proc_meminfo(&mut self.reader, &mut self.buf)?;
-        let pct = 100.0 * ((self.curr.total as f64 - self.curr.free as f64) / self.curr.total as f64);
-        Ok(pct)
+/// Whether time spent waiting for I/O counts towards CPU usage.
+#[derive(Clone, Copy)]
+pub enum Iowait {
+    Idle,
+    Busy,
+}
+
+impl str::FromStr for Iowait {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Iowait::Idle),
+            "busy" => Ok(Iowait::Busy),
+            _ => Err("accepts only idle or busy".to_owned()),
+        }
     }
 }
 
 pub struct ProcStat {
+    iowait: Iowait,
     reader: io::BufReader<fs::File>,
     buf: String,
-    curr: Measurement,
-    prev: Measurement,
 }
 
 impl ProcStat {
     const SEEK_TO_START: io::SeekFrom = io::SeekFrom::Start(0);
+    // Indexes of the fields following the "cpu" label.
+    const IDLE: usize = 3;
+    const IOWAIT: usize = 4;
 
-    pub fn new(f: fs::File) -> Self {
-        let mut s = Self {
-            reader: io::BufReader::with_capacity(8192, f),
-            buf: String::with_capacity(8192),
-            curr: Measurement::default(),
-            prev: Measurement::default(),
-        };
+    pub fn detect_capacity(f: &fs::File) -> usize {
+        detect_capacity(f)
+    }
 
-        // Try to initialize prev value.
-        if let Ok(val) = Self::parse_proc_stat(&mut s.reader, &mut s.buf) {
-            s.prev = val;
-            thread::sleep(time::Duration::from_millis(100));
+    pub fn with_capacity(f: fs::File, capacity: usize, iowait: Iowait) -> Self {
+        Self {
+            iowait,
+            reader: io::BufReader::with_capacity(capacity, f),
+            buf: String::with_capacity(capacity),
         }
-
-        s
     }
 
-    fn parse_proc_stat(reader: &mut io::BufReader<fs::File>, buf: &mut String) -> Result<Measurement> {
+    fn parse_proc_stat(reader: &mut io::BufReader<fs::File>, buf: &mut String, iowait: Iowait) -> Result<Measurement> {
         reader.seek(Self::SEEK_TO_START)?;
         buf.clear();
         let mut ct = Measurement::default();
@@ -178,8 +374,7 @@ impl ProcStat {
                 let val = val.parse::<u64>()?;
                 ct.total += val;
 
-                // 4th element is the idle time.
-                if i == 3 {
+                if i == Self::IDLE || (i == Self::IOWAIT && matches!(iowait, Iowait::Idle)) {
                     ct.free += val;
                 }
             }
@@ -188,23 +383,263 @@ impl ProcStat {
 
         Ok(ct)
     }
+
+    /// Numbers of the CPUs listed in /proc/stat, not counting the aggregate "cpu" line.
+    pub fn cpus(&mut self) -> Result<Vec<usize>> {
+        self.reader.seek(Self::SEEK_TO_START)?;
+        self.buf.clear();
+        let mut cpus = Vec::new();
+
+        while self.reader.read_line(&mut self.buf)? > 0 && self.buf.starts_with("cpu") {
+            if let Some(n) = self.buf[3..].split_whitespace().next().and_then(|n| n.parse().ok()) {
+                cpus.push(n);
+            }
+            self.buf.clear();
+        }
+        self.buf.clear();
+
+        Ok(cpus)
+    }
 }
 
-impl StatTaker for ProcStat {
-    fn measurement(&self) -> Measurement {
-        self.curr
+impl Source for ProcStat {
+    const KIND: StatKind = StatKind::Counter;
+
+    fn sample(&mut self) -> Result<Measurement> {
+        Self::parse_proc_stat(&mut self.reader, &mut self.buf, self.iowait)
     }
+}
 
-    fn measure(&mut self) -> Result<f64> {
-        self.curr = Self::parse_proc_stat(&mut self.reader, &mut self.buf)?;
+/// Where the kernel lists the topology of each CPU.
+pub const SYS_CPU: &str = "/sys/devices/system/cpu";
+
+/// Maps each of `cpus` to the physical core it is on, numbered in order of appearance, going by
+/// the package and core ids under `root`. Hyperthread siblings map to the same core. None if the
+/// topology of any of them can't be read.
+pub fn physical_cores(root: &Path, cpus: &[usize]) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    cpus.iter()
+        .map(|cpu| {
+            let topology = root.join(format!("cpu{}/topology", cpu));
+            let read = |name| fs::read_to_string(topology.join(name)).ok().map(|id| id.trim().to_owned());
+            let core = (read("physical_package_id")?, read("core_id")?);
+            Some(cores.iter().position(|c| *c == core).unwrap_or_else(|| {
+                cores.push(core);
+                cores.len() - 1
+            }))
+        })
+        .collect()
+}
 
-        let di = self.curr.free - self.prev.free;
-        let dt = self.curr.total - self.prev.total;
-        let pct = 100.0 * (1.0 - di as f64 / dt as f64);
+#[derive(Clone, Copy)]
+pub enum PoolType {
+    Zfs,
+    Btrfs,
+}
 
-        self.prev.free = self.curr.free;
-        self.prev.total = self.curr.total;
+impl str::FromStr for PoolType {
+    type Err = String;
 
-        Ok(pct)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zfs" => Ok(PoolType::Zfs),
+            "btrfs" => Ok(PoolType::Btrfs),
+            _ => Err("accepts only zfs or btrfs".to_owned()),
+        }
+    }
+}
+
+/// ZFS pool or Btrfs filesystem space usage, as reported by `zpool list` or `btrfs filesystem usage`.
+pub struct PoolUsage {
+    pool_type: PoolType,
+    target: String,
+}
+
+impl PoolUsage {
+    /// `target` is the pool name for ZFS, and the mount point for Btrfs.
+    pub fn new(pool_type: PoolType, target: String) -> Self {
+        Self { pool_type, target }
+    }
+
+    fn run(program: &str, args: &[&str]) -> Result<String> {
+        let output = Command::new(program).args(args).output()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => anyhow!("{} not found, is it installed?", program),
+                _ => e.into(),
+            })?;
+        if !output.status.success() {
+            bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    fn parse_zpool_list(out: &str) -> Result<Measurement> {
+        // -Hp -o size,free: tab separated, exact values in bytes.
+        let mut fields = out.split_whitespace();
+        let mut next = || -> Result<u64> {
+            Ok(fields.next().context("unexpected zpool list output")?.parse::<u64>()?)
+        };
+        let total = next()?;
+        let free = next()?;
+        Ok(Measurement { free, total })
+    }
+
+    fn parse_btrfs_usage(out: &str) -> Result<Measurement> {
+        let value = |key: &str| -> Result<u64> {
+            let line = out.lines().map(str::trim).find(|l| l.starts_with(key))
+                .with_context(|| format!("'{}' missing from btrfs filesystem usage output", key))?;
+            let value = line[key.len()..].split_whitespace().next()
+                .with_context(|| format!("no value for '{}' in btrfs filesystem usage output", key))?;
+            Ok(value.parse::<u64>()?)
+        };
+        let total = value("Device size:")?;
+        let used = value("Used:")?;
+        Ok(Measurement { free: total.saturating_sub(used), total })
+    }
+}
+
+impl Source for PoolUsage {
+    fn sample(&mut self) -> Result<Measurement> {
+        match self.pool_type {
+            PoolType::Zfs => {
+                Self::parse_zpool_list(&Self::run("zpool", &["list", "-Hp", "-o", "size,free", &self.target])?)
+            },
+            PoolType::Btrfs => {
+                Self::parse_btrfs_usage(&Self::run("btrfs", &["filesystem", "usage", "--raw", &self.target])?)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Measures the given percentages in order.
+    pub(crate) struct Fixed(pub VecDeque<f64>);
+
+    impl StatTaker for Fixed {
+        fn measurement(&self) -> Measurement {
+            Measurement::default()
+        }
+
+        fn measure(&mut self) -> Result<f64> {
+            Ok(self.0.pop_front().expect("measurement"))
+        }
+    }
+
+    pub(crate) fn fixed(pcts: &[f64]) -> Fixed {
+        Fixed(pcts.iter().copied().collect())
+    }
+
+    fn measure_all<S: StatTaker>(mut stat: S, n: usize) -> Vec<f64> {
+        (0..n).map(|_| stat.measure().unwrap()).collect()
+    }
+
+    #[test]
+    fn simple_moving_average() {
+        let stat = Smoothed::new(fixed(&[10.0, 20.0, 60.0, 0.0]), Smoothing::Simple(2));
+        assert_eq!(measure_all(stat, 4), [10.0, 15.0, 40.0, 30.0]);
+    }
+
+    #[test]
+    fn weighted_moving_average() {
+        let stat = Smoothed::new(fixed(&[10.0, 40.0, 70.0, 100.0]), Smoothing::Weighted(3));
+        // (10*1 + 40*2) / 3, (10*1 + 40*2 + 70*3) / 6, (40*1 + 70*2 + 100*3) / 6
+        assert_eq!(measure_all(stat, 4), [10.0, 30.0, 50.0, 80.0]);
+    }
+
+    #[test]
+    fn exponential_moving_average() {
+        let stat = Smoothed::new(fixed(&[10.0, 50.0, 50.0, 0.0]), Smoothing::Exponential(0.5));
+        // 10, 0.5*50 + 0.5*10, 0.5*50 + 0.5*30, 0.5*0 + 0.5*40
+        assert_eq!(measure_all(stat, 4), [10.0, 30.0, 40.0, 20.0]);
+    }
+
+    #[test]
+    fn exponential_alpha_one_is_unsmoothed() {
+        let stat = Smoothed::new(fixed(&[10.0, 50.0, 0.0]), Smoothing::Exponential(1.0));
+        assert_eq!(measure_all(stat, 3), [10.0, 50.0, 0.0]);
+    }
+
+    #[test]
+    fn reset_forgets_exponential_average() {
+        let mut stat = Smoothed::new(fixed(&[10.0, 90.0]), Smoothing::Exponential(0.5));
+        stat.measure().unwrap();
+        stat.reset();
+        assert_eq!(stat.measure().unwrap(), 90.0);
+    }
+
+    const BTRFS_USAGE: &str = "Overall:
+    Device size:\t\t\t1000000000
+    Device allocated:\t\t 600000000
+    Device unallocated:\t\t 400000000
+    Device missing:\t\t\t         0
+    Used:\t\t\t\t 250000000
+    Free (estimated):\t\t 700000000\t(min: 500000000)
+";
+
+    #[test]
+    fn btrfs_usage() {
+        let m = PoolUsage::parse_btrfs_usage(BTRFS_USAGE).unwrap();
+        assert_eq!((m.free, m.total), (750000000, 1000000000));
+    }
+
+    #[test]
+    fn btrfs_usage_missing_value() {
+        let out = BTRFS_USAGE.replace("250000000", "");
+        assert!(PoolUsage::parse_btrfs_usage(&out).is_err());
+        assert!(PoolUsage::parse_btrfs_usage("").is_err());
+    }
+
+    #[test]
+    fn zpool_list() {
+        let m = PoolUsage::parse_zpool_list("1000000000\t400000000\n").unwrap();
+        assert_eq!((m.free, m.total), (400000000, 1000000000));
+        assert!(PoolUsage::parse_zpool_list("1000000000\n").is_err());
+    }
+
+    /// Writes out a topology of `(cpu, package id, core id)`s in a new temporary directory.
+    fn topology(name: &str, cpus: &[(usize, u32, u32)]) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("swaybar-proc-graph-{}-{}", name, std::process::id()));
+        for (cpu, package, core) in cpus {
+            let dir = root.join(format!("cpu{}/topology", cpu));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("physical_package_id"), format!("{}\n", package)).unwrap();
+            fs::write(dir.join("core_id"), format!("{}\n", core)).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn physical_cores_smt() {
+        // Two packages of two cores with two threads each, siblings numbered apart like on x86.
+        let root = topology("smt", &[
+            (0, 0, 0), (1, 0, 1), (2, 1, 0), (3, 1, 1),
+            (4, 0, 0), (5, 0, 1), (6, 1, 0), (7, 1, 1),
+        ]);
+        let cores = physical_cores(&root, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        // An offline CPU missing from the list is skipped.
+        let without_cpu2 = physical_cores(&root, &[0, 1, 3, 4]);
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(cores, Some(vec![0, 1, 2, 3, 0, 1, 2, 3]));
+        assert_eq!(without_cpu2, Some(vec![0, 1, 2, 0]));
+    }
+
+    #[test]
+    fn physical_cores_missing_topology() {
+        let root = topology("missing", &[(0, 0, 0)]);
+        let cores = physical_cores(&root, &[0, 1]);
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(cores, None);
+    }
+
+    #[test]
+    fn reset_forgets_moving_average() {
+        let mut stat = Smoothed::new(fixed(&[10.0, 20.0, 90.0]), Smoothing::Simple(3));
+        stat.measure().unwrap();
+        stat.measure().unwrap();
+        stat.reset();
+        assert_eq!(stat.measure().unwrap(), 90.0);
     }
 }
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// The pids and /proc directories of the running processes.
+fn processes() -> impl Iterator<Item = (u32, PathBuf)> {
+    fs::read_dir("/proc").into_iter().flatten().flatten().filter_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse().ok()?;
+        Some((pid, entry.path()))
+    })
+}
+
+/// The name and the user plus system time in clock ticks, from the contents of /proc/<pid>/stat.
+fn parse_stat(stat: &str) -> Option<(&str, u64)> {
+    // The name is in parentheses, and may contain both spaces and parentheses itself.
+    let (head, tail) = stat.rsplit_once(')')?;
+    let (_, name) = head.split_once('(')?;
+    // utime and stime are the 14th and 15th fields, counting from the pid.
+    let mut fields = tail.split_whitespace().skip(11);
+    let utime: u64 = fields.next()?.parse().ok()?;
+    let stime: u64 = fields.next()?.parse().ok()?;
+    Some((name, utime + stime))
+}
+
+/// The name and resident set size in KiB, from the contents of /proc/<pid>/status. None for kernel
+/// threads, which have no VmRSS.
+fn parse_status(status: &str) -> Option<(&str, u64)> {
+    let mut name = None;
+    for line in status.lines() {
+        if let Some(n) = line.strip_prefix("Name:") {
+            name = Some(n.trim());
+        } else if let Some(rss) = line.strip_prefix("VmRSS:") {
+            let rss = rss.split_whitespace().next()?.parse().ok()?;
+            return Some((name?, rss));
+        }
+    }
+    None
+}
+
+/// Finds the process that used the most CPU time between two calls.
+#[derive(Default)]
+pub struct TopCpu {
+    prev: HashMap<u32, u64>,
+}
+
+impl TopCpu {
+    /// Name of the process that used the most CPU time since the previous call, if any did.
+    pub fn update(&mut self) -> Option<String> {
+        let mut curr = HashMap::with_capacity(self.prev.len());
+        let mut top: Option<(u64, String)> = None;
+
+        // Processes owned by other users may not be readable, and any may exit mid-scan.
+        for (pid, dir) in processes() {
+            let Ok(stat) = fs::read_to_string(dir.join("stat")) else { continue };
+            let Some((name, time)) = parse_stat(&stat) else { continue };
+            let used = self.prev.get(&pid).map_or(0, |&prev| time.saturating_sub(prev));
+            if used > top.as_ref().map_or(0, |(t, _)| *t) {
+                top = Some((used, name.to_owned()));
+            }
+            curr.insert(pid, time);
+        }
+
+        self.prev = curr;
+        top.map(|(_, name)| name)
+    }
+}
+
+/// Name of the process with the largest resident set.
+pub fn top_memory() -> Option<String> {
+    processes()
+        .filter_map(|(_, dir)| {
+            let status = fs::read_to_string(dir.join("status")).ok()?;
+            parse_status(&status).map(|(name, rss)| (rss, name.to_owned()))
+        })
+        .max_by_key(|(rss, _)| *rss)
+        .map(|(_, name)| name)
+}